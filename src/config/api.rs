@@ -1,13 +1,20 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::default::Default;
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 use std::fs;
 use std::io::Write;
 use std::path::PathBuf;
 use std::str::FromStr;
 
-use super::{prompt::Prompt, resolve_config_path};
+use super::{
+    prompt::Prompt,
+    resolve_config_path,
+    secrets::{
+        lookup_secret_service, read_key_file, run_key_command, warn_if_world_readable,
+        KEY_FILE_PREFIX,
+    },
+};
 
 const API_KEYS_FILE: &str = ".api_configs.toml";
 
@@ -22,6 +29,7 @@ pub enum Api {
     Openai,
     AzureOpenai,
     Cerebras,
+    Gemini,
 }
 
 impl FromStr for Api {
@@ -36,38 +44,52 @@ impl FromStr for Api {
             "groq" => Ok(Api::Groq),
             "anthropic" => Ok(Api::Anthropic),
             "cerebras" => Ok(Api::Cerebras),
+            "gemini" => Ok(Api::Gemini),
             _ => Err(()),
         }
     }
 }
 
-impl ToString for Api {
-    fn to_string(&self) -> String {
-        match self {
-            Api::Ollama => "ollama".to_string(),
-            Api::Openai => "openai".to_string(),
-            Api::AzureOpenai => "azureopenai".to_string(),
-            Api::Mistral => "mistral".to_string(),
-            Api::Groq => "groq".to_string(),
-            Api::Anthropic => "anthropic".to_string(),
-            Api::Cerebras => "cerebras".to_string(),
+impl fmt::Display for Api {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Api::Ollama => "ollama",
+            Api::Openai => "openai",
+            Api::AzureOpenai => "azureopenai",
+            Api::Mistral => "mistral",
+            Api::Groq => "groq",
+            Api::Anthropic => "anthropic",
+            Api::Cerebras => "cerebras",
+            Api::Gemini => "gemini",
             v => panic!(
                 "{:?} is not implemented, use one among {:?}",
                 v,
                 vec![Api::Openai]
             ),
-        }
+        };
+        write!(f, "{}", name)
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
 pub struct ApiConfig {
+    /// the key itself or `file:<path>` to read it from a file only you can read
     pub api_key: Option<String>,
     pub url: String,
+    /// name of the environment variable holding the key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key_env: Option<String>,
+    /// attributes of the key in the Secret Service, e.g. `{ service = "openai" }`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key_secret: Option<BTreeMap<String, String>>,
+    /// command printing the key, run once per process
     #[serde(skip_serializing_if = "Option::is_none")]
     pub api_key_command: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub default_model: Option<String>,
+    /// model used by `sc embed` and `sc search`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embedding_model: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub version: Option<String>,
     #[serde(
@@ -90,38 +112,51 @@ impl Default for ApiConfig {
 
 impl ApiConfig {
     pub fn get_api_key(&self) -> String {
-        self.api_key
-            .clone()
-            .or_else(|| {
-                self.api_key_command.clone().map(|command| {
-                    let output = if cfg!(windows) {
-                        std::process::Command::new("cmd")
-                            .arg("/c")
-                            .arg(command)
-                            .output()
-                    } else {
-                        std::process::Command::new("sh")
-                            .arg("-c")
-                            .arg(command)
-                            .output()
-                    }
-                    .expect("Failed to run the api command")
-                    .stdout;
-                    String::from_utf8(output)
-                        .expect("Invalid UTF-8 from command")
-                        .trim()
-                        .to_string()
-                })
-            })
+        if let Some(api_key) = &self.api_key {
+            return match api_key.strip_prefix(KEY_FILE_PREFIX) {
+                Some(path) => read_key_file(path),
+                None => api_key.clone(),
+            };
+        }
+        if let Some(var) = &self.api_key_env {
+            return std::env::var(var).unwrap_or_else(|_| {
+                panic!(
+                    "Environment variable {} holding the api key is not set",
+                    var
+                )
+            });
+        }
+        if let Some(attributes) = &self.api_key_secret {
+            return lookup_secret_service(attributes);
+        }
+        self.api_key_command
+            .as_deref()
+            .map(run_key_command)
             .unwrap_or_default()
     }
 
+    pub fn has_api_key_source(&self) -> bool {
+        self.api_key.is_some()
+            || self.api_key_env.is_some()
+            || self.api_key_secret.is_some()
+            || self.api_key_command.is_some()
+    }
+
+    fn has_plaintext_key(&self) -> bool {
+        self.api_key
+            .as_ref()
+            .is_some_and(|api_key| !api_key.starts_with(KEY_FILE_PREFIX))
+    }
+
     pub(super) fn ollama() -> Self {
         ApiConfig {
             api_key_command: None,
+            api_key_env: None,
+            api_key_secret: None,
             api_key: None,
             url: String::from("http://localhost:11434/api/chat"),
             default_model: Some(String::from("phi3")),
+            embedding_model: Some(String::from("nomic-embed-text")),
             version: None,
             timeout_seconds: Some(180),
         }
@@ -130,9 +165,12 @@ impl ApiConfig {
     pub(super) fn openai() -> Self {
         ApiConfig {
             api_key_command: None,
+            api_key_env: None,
+            api_key_secret: None,
             api_key: None,
             url: String::from("https://api.openai.com/v1/chat/completions"),
             default_model: Some(String::from("gpt-4")),
+            embedding_model: Some(String::from("text-embedding-3-small")),
             version: None,
             timeout_seconds: None,
         }
@@ -141,9 +179,12 @@ impl ApiConfig {
     pub(super) fn azureopenai() -> Self {
         ApiConfig {
             api_key_command: None,
+            api_key_env: None,
+            api_key_secret: None,
             api_key: None,
             url: String::from("https://your-azure-endpoint.azure.com/openai/deployments/your-deployment-id/chat/completions?api-version=2024-06-01"),
             default_model: Some(String::from("gpt-4o")),
+            embedding_model: None,
             version: None,
             timeout_seconds: None,
         }
@@ -152,9 +193,12 @@ impl ApiConfig {
     pub(super) fn mistral() -> Self {
         ApiConfig {
             api_key_command: None,
+            api_key_env: None,
+            api_key_secret: None,
             api_key: None,
             url: String::from("https://api.mistral.ai/v1/chat/completions"),
             default_model: Some(String::from("mistral-medium")),
+            embedding_model: Some(String::from("mistral-embed")),
             version: None,
             timeout_seconds: None,
         }
@@ -163,9 +207,12 @@ impl ApiConfig {
     pub(super) fn groq() -> Self {
         ApiConfig {
             api_key_command: None,
+            api_key_env: None,
+            api_key_secret: None,
             api_key: None,
             url: String::from("https://api.groq.com/openai/v1/chat/completions"),
             default_model: Some(String::from("llama3-70b-8192")),
+            embedding_model: None,
             version: None,
             timeout_seconds: None,
         }
@@ -174,9 +221,12 @@ impl ApiConfig {
     pub(super) fn anthropic() -> Self {
         ApiConfig {
             api_key_command: None,
+            api_key_env: None,
+            api_key_secret: None,
             api_key: None,
             url: String::from("https://api.anthropic.com/v1/messages"),
             default_model: Some(String::from("claude-3-opus-20240229")),
+            embedding_model: None,
             version: Some(String::from("2023-06-01")),
             timeout_seconds: None,
         }
@@ -185,9 +235,27 @@ impl ApiConfig {
     pub(super) fn cerebras() -> Self {
         ApiConfig {
             api_key_command: None,
+            api_key_env: None,
+            api_key_secret: None,
             api_key: None,
             url: String::from("https://api.cerebras.ai/v1/chat/completions"),
             default_model: Some(String::from("llama3.1-70b")),
+            embedding_model: None,
+            version: None,
+            timeout_seconds: None,
+        }
+    }
+
+    /// the model and `:generateContent` are appended to the url
+    pub(super) fn gemini() -> Self {
+        ApiConfig {
+            api_key_command: None,
+            api_key_env: None,
+            api_key_secret: None,
+            api_key: None,
+            url: String::from("https://generativelanguage.googleapis.com/v1beta/models"),
+            default_model: Some(String::from("gemini-2.0-flash")),
+            embedding_model: Some(String::from("text-embedding-004")),
             version: None,
             timeout_seconds: None,
         }
@@ -207,6 +275,7 @@ pub(super) fn generate_api_keys_file() -> std::io::Result<()> {
     api_config.insert(Api::Groq.to_string(), ApiConfig::groq());
     api_config.insert(Api::Anthropic.to_string(), ApiConfig::anthropic());
     api_config.insert(Api::Cerebras.to_string(), ApiConfig::cerebras());
+    api_config.insert(Api::Gemini.to_string(), ApiConfig::gemini());
 
     // Default, should override one of the above
     api_config.insert(Prompt::default().api.to_string(), ApiConfig::default());
@@ -214,27 +283,42 @@ pub(super) fn generate_api_keys_file() -> std::io::Result<()> {
     std::fs::create_dir_all(api_keys_path().parent().unwrap())?;
 
     let mut config_file = fs::File::create(api_keys_path())?;
+    // the file is meant to hold keys, keep it private
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        config_file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
 
     {
         let api_key_doc = "\
-        # Api config files, use `api_key` or `api_key_command` fields\n\
-        # to set the api key for each api\n\
+        # Api config files, use `api_key`, `api_key_env`, `api_key_secret` or `api_key_command`\n\
+        # fields to set the api key for each api\n\
         # more details at https://github.com/efugier/smartcat#configuration\n\n";
         config_file.write_all(api_key_doc.as_bytes())?;
     }
 
-    let api_config_str = toml::to_string_pretty(&api_config)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    let api_config_str = toml::to_string_pretty(&api_config).map_err(std::io::Error::other)?;
     config_file.write_all(api_config_str.as_bytes())?;
 
     Ok(())
 }
 
-pub fn get_api_config(api: &str) -> ApiConfig {
+/// every api config of the config file, keyed by api name
+pub fn get_api_configs() -> HashMap<String, ApiConfig> {
     let content = fs::read_to_string(api_keys_path())
         .unwrap_or_else(|error| panic!("Could not read file {:?}, {:?}", api_keys_path(), error));
 
-    let mut api_configs: HashMap<String, ApiConfig> = toml::from_str(&content).unwrap();
+    let api_configs: HashMap<String, ApiConfig> = toml::from_str(&content).unwrap();
+    warn_if_world_readable(
+        &api_keys_path(),
+        api_configs.values().any(ApiConfig::has_plaintext_key),
+    );
+    api_configs
+}
+
+pub fn get_api_config(api: &str) -> ApiConfig {
+    let mut api_configs = get_api_configs();
 
     api_configs.remove(api).unwrap_or_else(|| {
         panic!(
@@ -1,5 +1,7 @@
 pub mod api;
 pub mod prompt;
+mod secrets;
+pub mod settings;
 
 use std::{path::PathBuf, process::Command};
 
@@ -48,10 +50,12 @@ pub fn ensure_config_files() -> std::io::Result<()> {
     }
 
     if !api_keys_path().exists() {
-        println!(
-            "API config file not found at {}, generating one.\n...",
-            api_keys_path().display()
-        );
+        if interactive {
+            println!(
+                "API config file not found at {}, generating one.\n...",
+                api_keys_path().display()
+            );
+        }
         generate_api_keys_file().expect("Unable to generate config files");
         if interactive {
             ensure_config_usable();
@@ -67,7 +71,7 @@ pub fn ensure_config_usable() {
     // check if any config has an API key;
     let third_parth_config_usable = get_prompts().iter().any(|(_, prompt)| {
         let api = get_api_config(&prompt.api.to_string());
-        api.api_key.is_some() || api.api_key_command.is_some()
+        api.has_api_key_source()
     });
     if !third_parth_config_usable {
         println!(
@@ -107,8 +111,10 @@ mod tests {
         config::{
             api::{api_keys_path, default_timeout_seconds, Api, ApiConfig},
             ensure_config_files,
-            prompt::{prompts_path, Prompt},
-            resolve_config_path, CUSTOM_CONFIG_ENV_VAR, DEFAULT_CONFIG_PATH,
+            prompt::{bundled_prompts, get_prompts, prompts_path, Backend, Message, Prompt},
+            resolve_config_path,
+            settings::{get_settings, settings_path, CacheSettings, Settings},
+            CUSTOM_CONFIG_ENV_VAR, DEFAULT_CONFIG_PATH,
         },
         utils::IS_NONINTERACTIVE_ENV_VAR,
     };
@@ -302,4 +308,169 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    #[serial]
+    fn test_bundled_prompts_follow_default_and_can_be_overridden() -> std::io::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let original_value = env::var(CUSTOM_CONFIG_ENV_VAR);
+        env::set_var(CUSTOM_CONFIG_ENV_VAR, temp_dir.path());
+
+        fs::write(
+            prompts_path(),
+            "[default]\napi = \"anthropic\"\nmodel = \"claude\"\nmessages = []\n\n\
+            [review]\napi = \"groq\"\nmessages = []\n",
+        )?;
+        let prompts = get_prompts();
+
+        match original_value {
+            Ok(val) => env::set_var(CUSTOM_CONFIG_ENV_VAR, val),
+            Err(_) => env::remove_var(CUSTOM_CONFIG_ENV_VAR),
+        }
+
+        let commit_msg = prompts.get("commit-msg").unwrap();
+        assert_eq!(commit_msg.api, Api::Anthropic);
+        assert_eq!(commit_msg.model, Some("claude".to_string()));
+        assert_eq!(
+            commit_msg.messages,
+            bundled_prompts().get("commit-msg").unwrap().messages
+        );
+
+        let review = prompts.get("review").unwrap();
+        assert_eq!(review.api, Api::Groq);
+        assert!(review.messages.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_settings_default_when_missing_or_partial() -> std::io::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let original_value = env::var(CUSTOM_CONFIG_ENV_VAR);
+        env::set_var(CUSTOM_CONFIG_ENV_VAR, temp_dir.path());
+
+        let missing_file_settings = get_settings();
+        fs::write(settings_path(), "[cache]\nenabled = true\n")?;
+        let partial_file_settings = get_settings();
+
+        match original_value {
+            Ok(val) => env::set_var(CUSTOM_CONFIG_ENV_VAR, val),
+            Err(_) => env::remove_var(CUSTOM_CONFIG_ENV_VAR),
+        }
+
+        assert_eq!(missing_file_settings, Settings::default());
+        assert_eq!(
+            partial_file_settings.cache,
+            CacheSettings {
+                enabled: true,
+                ..CacheSettings::default()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prompt_fallback_parsing() {
+        let prompt: Prompt = toml::from_str(
+            "api = \"anthropic\"\n\
+            messages = []\n\
+            fallback = [{api = \"openai\", model = \"gpt-4o\"}, {api = \"ollama\"}]\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            prompt.fallback,
+            vec![
+                Backend {
+                    api: Api::Openai,
+                    model: Some("gpt-4o".to_string())
+                },
+                Backend {
+                    api: Api::Ollama,
+                    model: None
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_aliases_resolution() {
+        let settings: Settings = toml::from_str(
+            "[aliases]\n\
+            fast = {api = \"groq\", model = \"llama3-70b-8192\"}\n\
+            latest = {model = \"gpt-4o\"}\n",
+        )
+        .unwrap();
+        let mut prompt = Prompt {
+            api: Api::Openai,
+            model: Some("fast".to_string()),
+            fallback: vec![
+                Backend {
+                    api: Api::Openai,
+                    model: Some("latest".to_string()),
+                },
+                Backend {
+                    api: Api::Ollama,
+                    model: Some("phi3".to_string()),
+                },
+            ],
+            ..Prompt::empty()
+        };
+
+        let mut explicit_api_prompt = Prompt {
+            api: Api::Openai,
+            model: Some("fast".to_string()),
+            ..Prompt::empty()
+        };
+
+        prompt.resolve_aliases(&settings.aliases, None);
+        explicit_api_prompt.resolve_aliases(&settings.aliases, Some(&Api::Openai));
+
+        assert_eq!(prompt.api, Api::Groq);
+        assert_eq!(prompt.model, Some("llama3-70b-8192".to_string()));
+        assert_eq!(
+            prompt.fallback,
+            vec![
+                Backend {
+                    api: Api::Openai,
+                    model: Some("gpt-4o".to_string())
+                },
+                Backend {
+                    api: Api::Ollama,
+                    model: Some("phi3".to_string())
+                },
+            ]
+        );
+        assert_eq!(explicit_api_prompt.api, Api::Openai);
+        assert_eq!(
+            explicit_api_prompt.model,
+            Some("llama3-70b-8192".to_string())
+        );
+    }
+
+    #[test]
+    fn test_pop_last_answer_and_exchange() {
+        let mut prompt = Prompt {
+            messages: vec![
+                Message::system("be brief"),
+                Message::user("hi"),
+                Message::assistant("hello"),
+                Message::user("how are you?"),
+                Message::assistant("fine"),
+            ],
+            ..Prompt::empty()
+        };
+
+        assert!(prompt.pop_last_answer());
+        assert_eq!(prompt.messages.last(), Some(&Message::user("how are you?")));
+        assert!(!prompt.pop_last_answer());
+
+        assert!(prompt.pop_last_exchange());
+        assert_eq!(prompt.messages.last(), Some(&Message::assistant("hello")));
+        assert!(prompt.pop_last_exchange());
+        assert_eq!(prompt.messages, vec![Message::system("be brief")]);
+        assert!(!prompt.pop_last_exchange());
+    }
 }
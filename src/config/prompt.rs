@@ -1,12 +1,13 @@
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::collections::HashMap;
 use std::default::Default;
 use std::fmt::Debug;
 use std::fs;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use crate::config::{api::Api, resolve_config_path};
+use crate::config::{api::Api, resolve_config_path, settings::ModelAlias};
 
 const PROMPT_FILE: &str = "prompts.toml";
 const CONVERSATION_FILE: &str = "conversation.toml";
@@ -22,6 +23,50 @@ pub struct Prompt {
     pub char_limit: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stream: Option<bool>, // unsuported for now
+    /// max number of tokens of the answer, 4096 by default for anthropic which requires it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    /// anthropic and ollama only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    /// sequences at which the generation stops
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    /// not supported by anthropic
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    /// not supported by anthropic
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    /// ollama `options` such as `num_ctx`, see https://github.com/ollama/ollama/blob/main/docs/modelfile.md#parameter
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<Map<String, Value>>,
+    /// ollama only, how long the model stays loaded after the request, e.g. "30m"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_alive: Option<String>,
+    /// merged into the request body, for the parameters smartcat doesn't handle
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra_body: Option<Map<String, Value>>,
+    /// backends to try in order when the main one fails with a retryable error
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallback: Vec<Backend>,
+    /// when the answer is cut by the max tokens, ask for the rest and stitch it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub auto_continue: bool,
+    /// markdown, json or jsonl file of messages or input/output examples added before the input,
+    /// relative to the config directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub examples_file: Option<PathBuf>,
+}
+
+/// an api and optionally which of its model to use, default model of the api config otherwise
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct Backend {
+    pub api: Api,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
 }
 
 impl Default for Prompt {
@@ -47,6 +92,18 @@ impl Default for Prompt {
             messages,
             stream: None,
             char_limit: Some(50000),
+            max_tokens: None,
+            top_p: None,
+            top_k: None,
+            stop: Vec::new(),
+            seed: None,
+            presence_penalty: None,
+            options: None,
+            keep_alive: None,
+            extra_body: None,
+            fallback: Vec::new(),
+            auto_continue: false,
+            examples_file: None,
         }
     }
 }
@@ -61,7 +118,75 @@ impl Prompt {
             messages: vec![],
             stream: None,
             char_limit: Some(50000),
+            max_tokens: None,
+            top_p: None,
+            top_k: None,
+            stop: Vec::new(),
+            seed: None,
+            presence_penalty: None,
+            options: None,
+            keep_alive: None,
+            extra_body: None,
+            fallback: Vec::new(),
+            auto_continue: false,
+            examples_file: None,
+        }
+    }
+
+    /// Replace the model and fallback models which are aliases, switching api if the alias
+    /// names one, except for the model when the api was given explicitly, e.g. with `--api`.
+    pub fn resolve_aliases(
+        &mut self,
+        aliases: &HashMap<String, ModelAlias>,
+        explicit_api: Option<&Api>,
+    ) {
+        if let Some(alias) = self.model.as_ref().and_then(|model| aliases.get(model)) {
+            self.api = explicit_api
+                .or(alias.api.as_ref())
+                .unwrap_or(&self.api)
+                .clone();
+            self.model = Some(alias.model.clone());
         }
+        for backend in self.fallback.iter_mut() {
+            if let Some(alias) = backend.model.as_ref().and_then(|model| aliases.get(model)) {
+                backend.api = alias.api.clone().unwrap_or(backend.api.clone());
+                backend.model = Some(alias.model.clone());
+            }
+        }
+    }
+
+    /// remove the answers closing the conversation, returns whether there was any
+    pub fn pop_last_answer(&mut self) -> bool {
+        let length = self.messages.len();
+        while self
+            .messages
+            .last()
+            .is_some_and(|message| message.role == "assistant")
+        {
+            self.messages.pop();
+        }
+        self.messages.len() < length
+    }
+
+    /// remove the last answer and the user message it answered, returns whether there was any
+    pub fn pop_last_exchange(&mut self) -> bool {
+        let popped_answer = self.pop_last_answer();
+        if self
+            .messages
+            .last()
+            .is_some_and(|message| message.role == "user")
+        {
+            self.messages.pop();
+            return true;
+        }
+        popped_answer
+    }
+
+    /// the examples file, relative paths being resolved from the config directory
+    pub fn examples_path(&self) -> Option<PathBuf> {
+        self.examples_file
+            .as_ref()
+            .map(|path| resolve_config_path().join(path))
     }
 }
 
@@ -69,6 +194,37 @@ impl Prompt {
 pub struct Message {
     pub role: String,
     pub content: String,
+    /// smartcat's own info about the message, never sent to the apis
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<MessageMetadata>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct MessageMetadata {
+    /// backend which actually wrote the answer, may be a fallback one
+    pub answered_by: Backend,
+    /// unix time at which the answer was received, or served from the cache
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    /// the answer was served from the cache rather than by the api
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cached: bool,
+    /// tokens billed for the answer, as reported by the api
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<TokenUsage>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default)]
+pub struct TokenUsage {
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+}
+
+impl std::ops::AddAssign for TokenUsage {
+    fn add_assign(&mut self, other: TokenUsage) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+    }
 }
 
 impl Message {
@@ -76,18 +232,27 @@ impl Message {
         Message {
             role: "user".to_string(),
             content: content.to_string(),
+            metadata: None,
         }
     }
     pub fn system(content: &str) -> Message {
         Message {
             role: "system".to_string(),
             content: content.to_string(),
+            metadata: None,
         }
     }
     pub fn assistant(content: &str) -> Message {
         Message {
             role: "assistant".to_string(),
             content: content.to_string(),
+            metadata: None,
+        }
+    }
+    pub fn without_metadata(self) -> Message {
+        Message {
+            metadata: None,
+            ..self
         }
     }
 }
@@ -101,16 +266,21 @@ pub fn conversation_file_path() -> PathBuf {
 }
 
 pub fn get_last_conversation_as_prompt() -> Prompt {
-    let content = fs::read_to_string(conversation_file_path()).unwrap_or_else(|error| {
-        panic!(
-            "Could not read file {:?}, {:?}",
-            conversation_file_path(),
-            error
-        )
-    });
+    load_conversation(&conversation_file_path())
+}
+
+pub fn load_conversation(path: &Path) -> Prompt {
+    let content = fs::read_to_string(path)
+        .unwrap_or_else(|error| panic!("Could not read file {:?}, {:?}", path, error));
     toml::from_str(&content).expect("failed to load the conversation file")
 }
 
+/// save the conversation for it to be extended with `-e`
+pub fn save_conversation(prompt: &Prompt) -> std::io::Result<()> {
+    let toml_string = toml::to_string(prompt).expect("Failed to serialize prompt after response.");
+    fs::write(conversation_file_path(), toml_string)
+}
+
 pub(super) fn generate_prompts_file() -> std::io::Result<()> {
     let mut prompt_config = HashMap::new();
     prompt_config.insert("default", Prompt::default());
@@ -125,14 +295,58 @@ pub(super) fn generate_prompts_file() -> std::io::Result<()> {
         # more details and examples at https://github.com/efugier/smartcat#configuration\n\n";
     prompts_file.write_all(doc.as_bytes())?;
 
-    let prompt_str = toml::to_string_pretty(&prompt_config)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    let prompt_str = toml::to_string_pretty(&prompt_config).map_err(std::io::Error::other)?;
     prompts_file.write_all(prompt_str.as_bytes())?;
     Ok(())
 }
 
+/// Templates shipped with smartcat, meant to be used with the git context flags.
+/// They are not written to the prompts file, define a prompt with the same name to override one.
+pub(super) fn bundled_prompts() -> HashMap<String, Prompt> {
+    let commit_msg = Prompt {
+        messages: vec![Message::system(
+            "\
+            You write git commit messages. Given the changes provided as context, write a commit message \
+            made of a summary line in the imperative mood of at most 72 characters, a blank line, \
+            then a short body explaining what changed and why when it isn't obvious from the summary. \
+            Follow the conventions visible in the recent commits if some are provided. \
+            Write only the commit message, no comment, no markdown delimiters.\
+            ",
+        )],
+        ..Prompt::empty()
+    };
+    let review = Prompt {
+        messages: vec![Message::system(
+            "\
+            You are an extremely skilled programmer reviewing a change. Given the changes provided as context, \
+            list the bugs, risky behaviors, missing tests and readability issues you find, most important first, \
+            each one referencing the file and the lines concerned. Be concise and skip praise. \
+            If the change looks good, say so in one sentence.\
+            ",
+        )],
+        ..Prompt::empty()
+    };
+
+    HashMap::from([
+        ("commit-msg".to_string(), commit_msg),
+        ("review".to_string(), review),
+    ])
+}
+
 pub fn get_prompts() -> HashMap<String, Prompt> {
     let content = fs::read_to_string(prompts_path())
         .unwrap_or_else(|error| panic!("Could not read file {:?}, {:?}", prompts_path(), error));
-    toml::from_str(&content).expect("could not parse prompt file content")
+    let mut prompts: HashMap<String, Prompt> =
+        toml::from_str(&content).expect("could not parse prompt file content");
+
+    // bundled templates use the same api and model as the user's default prompt
+    let default_prompt = prompts.get("default").cloned().unwrap_or_default();
+    for (name, prompt) in bundled_prompts() {
+        prompts.entry(name).or_insert(Prompt {
+            api: default_prompt.api.clone(),
+            model: default_prompt.model.clone(),
+            ..prompt
+        });
+    }
+    prompts
 }
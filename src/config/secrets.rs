@@ -0,0 +1,375 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Mutex, OnceLock};
+
+/// prefix of an `api_key` value pointing to a file holding the key
+pub const KEY_FILE_PREFIX: &str = "file:";
+
+/// `api_key_command` outputs, the commands are only run once per process
+static COMMAND_OUTPUTS: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+
+pub fn run_key_command(command: &str) -> String {
+    let mut outputs = COMMAND_OUTPUTS
+        .get_or_init(Default::default)
+        .lock()
+        .expect("api key command cache poisoned");
+    outputs
+        .entry(command.to_string())
+        .or_insert_with(|| {
+            let output = if cfg!(windows) {
+                Command::new("cmd").arg("/c").arg(command).output()
+            } else {
+                Command::new("sh").arg("-c").arg(command).output()
+            }
+            .expect("Failed to run the api command")
+            .stdout;
+            String::from_utf8(output)
+                .expect("Invalid UTF-8 from command")
+                .trim()
+                .to_string()
+        })
+        .clone()
+}
+
+/// Read the key from a file, refusing files other users can read, like ssh does.
+pub fn read_key_file(path: &str) -> String {
+    let path = expand_home(path);
+    check_private(&path);
+    fs::read_to_string(&path)
+        .unwrap_or_else(|error| panic!("Could not read api key file {:?}, {:?}", path, error))
+        .trim()
+        .to_string()
+}
+
+/// Look the key up in the freedesktop Secret Service (gnome-keyring, kwallet...),
+/// e.g. stored with `secret-tool store --label=openai service openai`
+pub fn lookup_secret_service(attributes: &BTreeMap<String, String>) -> String {
+    secret_service_lookup(attributes).unwrap_or_else(|error| panic!("{}", error))
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn secret_service_lookup(attributes: &BTreeMap<String, String>) -> Result<String, String> {
+    use secret_service::{blocking::SecretService, EncryptionType};
+
+    let service = SecretService::connect(EncryptionType::Dh)
+        .map_err(|error| format!("Could not connect to the Secret Service, {}", error))?;
+    let items = service
+        .search_items(
+            attributes
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect(),
+        )
+        .map_err(|error| format!("Could not search the Secret Service, {}", error))?;
+    let item = match (items.unlocked.first(), items.locked.first()) {
+        (Some(item), _) => item,
+        (None, Some(item)) => {
+            item.unlock()
+                .map_err(|error| format!("Could not unlock the secret, {}", error))?;
+            item
+        }
+        (None, None) => {
+            return Err(format!(
+                "No secret found in the Secret Service for attributes {:?}",
+                attributes
+            ))
+        }
+    };
+    let secret = item
+        .get_secret()
+        .map_err(|error| format!("Could not read the secret, {}", error))?;
+    String::from_utf8(secret)
+        .map(|secret| secret.trim().to_string())
+        .map_err(|_| "Invalid UTF-8 in the secret".to_string())
+}
+
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn secret_service_lookup(_attributes: &BTreeMap<String, String>) -> Result<String, String> {
+    Err("The Secret Service is only available on Linux and the BSDs".to_string())
+}
+
+/// Warn if the api config file can be read by anyone while it contains keys in clear
+pub fn warn_if_world_readable(config_path: &Path, contains_plaintext_keys: bool) {
+    static WARNED: OnceLock<()> = OnceLock::new();
+    if let Some(warning) = world_readable_warning(config_path, contains_plaintext_keys) {
+        WARNED.get_or_init(|| eprintln!("{}", warning));
+    }
+}
+
+fn world_readable_warning(config_path: &Path, contains_plaintext_keys: bool) -> Option<String> {
+    (contains_plaintext_keys && is_world_readable(config_path)).then(|| {
+        format!(
+            "Warning: {} contains api keys and is readable by every user, \
+            restrict it with `chmod 600 {}` or use `api_key_env`, `api_key_command` \
+            or a `file:` api key instead.",
+            config_path.display(),
+            config_path.display()
+        )
+    })
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var("HOME")) {
+        (Some(relative_path), Ok(home)) => PathBuf::from(home).join(relative_path),
+        _ => PathBuf::from(path),
+    }
+}
+
+#[cfg(unix)]
+fn check_private(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Ok(metadata) = fs::metadata(path) {
+        let mode = metadata.permissions().mode();
+        if mode & 0o077 != 0 {
+            panic!(
+                "Api key file {:?} is accessible by other users (mode {:o}), \
+                restrict it with `chmod 600 {}`",
+                path,
+                mode & 0o777,
+                path.display()
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn check_private(_path: &Path) {}
+
+#[cfg(unix)]
+fn is_world_readable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::metadata(path).is_ok_and(|metadata| metadata.permissions().mode() & 0o004 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_world_readable(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::api::ApiConfig;
+    use serial_test::serial;
+
+    fn config_with(api_key: Option<String>, api_key_env: Option<String>) -> ApiConfig {
+        ApiConfig {
+            api_key,
+            api_key_env,
+            ..ApiConfig::default()
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_api_key_from_env() {
+        std::env::set_var("SMARTCAT_TEST_API_KEY_FROM_ENV", "env_key");
+        let config = config_with(None, Some("SMARTCAT_TEST_API_KEY_FROM_ENV".to_string()));
+
+        assert_eq!(config.get_api_key(), "env_key");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_api_key_from_private_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let key_path = dir.path().join("key");
+        fs::write(&key_path, "file_key\n").unwrap();
+        fs::set_permissions(&key_path, fs::Permissions::from_mode(0o600)).unwrap();
+        let config = config_with(
+            Some(format!("{}{}", KEY_FILE_PREFIX, key_path.display())),
+            None,
+        );
+
+        assert_eq!(config.get_api_key(), "file_key");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[should_panic(expected = "accessible by other users")]
+    fn test_api_key_file_readable_by_others_is_refused() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let key_path = dir.path().join("key");
+        fs::write(&key_path, "file_key").unwrap();
+        fs::set_permissions(&key_path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        read_key_file(key_path.to_str().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_api_key_command_runs_once() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let runs_path = dir.path().join("runs");
+        let command = format!("echo run >> {}; echo command_key", runs_path.display());
+
+        assert_eq!(run_key_command(&command), "command_key");
+        assert_eq!(run_key_command(&command), "command_key");
+        assert_eq!(fs::read_to_string(runs_path).unwrap(), "run\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_world_readable_warning() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let config_path = dir.path().join(".api_configs.toml");
+        fs::write(&config_path, "[openai]\napi_key = \"key\"\n").unwrap();
+
+        fs::set_permissions(&config_path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(world_readable_warning(&config_path, true)
+            .unwrap()
+            .contains("chmod 600"));
+        assert_eq!(world_readable_warning(&config_path, false), None);
+
+        fs::set_permissions(&config_path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(world_readable_warning(&config_path, true), None);
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    #[test]
+    #[serial]
+    fn test_lookup_secret_service_without_a_service() {
+        let original_value = std::env::var("DBUS_SESSION_BUS_ADDRESS");
+        std::env::set_var(
+            "DBUS_SESSION_BUS_ADDRESS",
+            "unix:path=/nonexistent/smartcat-test-bus",
+        );
+        let attributes = BTreeMap::from([("service".to_string(), "openai".to_string())]);
+
+        let result = secret_service_lookup(&attributes);
+        let lookup = std::panic::catch_unwind(|| lookup_secret_service(&attributes));
+
+        match original_value {
+            Ok(value) => std::env::set_var("DBUS_SESSION_BUS_ADDRESS", value),
+            Err(_) => std::env::remove_var("DBUS_SESSION_BUS_ADDRESS"),
+        }
+        assert!(result
+            .unwrap_err()
+            .starts_with("Could not connect to the Secret Service"));
+        assert!(lookup.is_err());
+    }
+
+    /// Secret Service holding a single item, answering the key exchange with
+    /// the public key 1 so the shared key is known and the item can be stored
+    /// already encrypted
+    #[cfg(all(unix, not(target_os = "macos")))]
+    mod stand_in {
+        use std::collections::HashMap;
+        use zbus::zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Value};
+
+        pub const ITEM_PATH: &str = "/org/freedesktop/secrets/collection/login/1";
+
+        pub struct SecretService;
+
+        #[zbus::interface(name = "org.freedesktop.Secret.Service")]
+        impl SecretService {
+            fn open_session(
+                &self,
+                _algorithm: &str,
+                _input: Value<'_>,
+            ) -> (OwnedValue, OwnedObjectPath) {
+                (
+                    Value::from(vec![1u8]).try_into().unwrap(),
+                    ObjectPath::try_from("/org/freedesktop/secrets/session/1")
+                        .unwrap()
+                        .into(),
+                )
+            }
+
+            fn search_items(
+                &self,
+                attributes: HashMap<String, String>,
+            ) -> (Vec<OwnedObjectPath>, Vec<OwnedObjectPath>) {
+                let matches = attributes.get("service").map(String::as_str) == Some("openai");
+                let item_path = ObjectPath::try_from(ITEM_PATH).unwrap();
+                (
+                    matches.then(|| item_path.into()).into_iter().collect(),
+                    Vec::new(),
+                )
+            }
+        }
+
+        pub struct Item;
+
+        #[zbus::interface(name = "org.freedesktop.Secret.Item")]
+        impl Item {
+            fn get_secret(
+                &self,
+                session: OwnedObjectPath,
+            ) -> ((OwnedObjectPath, Vec<u8>, Vec<u8>, String),) {
+                // "stored-key" encrypted with aes-128-cbc, with the key derived
+                // from the shared secret 1 and a zero iv
+                let encrypted = vec![
+                    0x64, 0xd2, 0x16, 0x13, 0x78, 0xdf, 0x64, 0xb4, 0x06, 0x60, 0x0f, 0x59, 0x8c,
+                    0xe1, 0xd8, 0xef,
+                ];
+                ((session, vec![0; 16], encrypted, "text/plain".to_string()),)
+            }
+        }
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    #[test]
+    #[serial]
+    fn test_lookup_secret_service_with_a_stand_in_service() {
+        use std::io::BufRead;
+        use std::process::{Command, Stdio};
+
+        let Ok(mut bus) = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address=1"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        else {
+            eprintln!("dbus-daemon isn't installed, skipping the test");
+            return;
+        };
+        let mut address = String::new();
+        std::io::BufReader::new(bus.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        let address = address.trim().to_string();
+
+        let service = zbus::blocking::connection::Builder::address(address.as_str())
+            .unwrap()
+            .name("org.freedesktop.secrets")
+            .unwrap()
+            .serve_at("/org/freedesktop/secrets", stand_in::SecretService)
+            .unwrap()
+            .serve_at(stand_in::ITEM_PATH, stand_in::Item)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let original_value = std::env::var("DBUS_SESSION_BUS_ADDRESS");
+        std::env::set_var("DBUS_SESSION_BUS_ADDRESS", &address);
+        let found = secret_service_lookup(&BTreeMap::from([(
+            "service".to_string(),
+            "openai".to_string(),
+        )]));
+        let missing = secret_service_lookup(&BTreeMap::from([(
+            "service".to_string(),
+            "mistral".to_string(),
+        )]));
+        match original_value {
+            Ok(value) => std::env::set_var("DBUS_SESSION_BUS_ADDRESS", value),
+            Err(_) => std::env::remove_var("DBUS_SESSION_BUS_ADDRESS"),
+        }
+        drop(service);
+        let _ = bus.kill();
+        let _ = bus.wait();
+
+        assert_eq!(found, Ok("stored-key".to_string()));
+        assert!(missing.unwrap_err().starts_with("No secret found"));
+    }
+}
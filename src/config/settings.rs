@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use super::{api::Api, resolve_config_path};
+
+const SETTINGS_FILE: &str = "settings.toml";
+const CACHE_DIR: &str = "cache";
+const INDEX_DIR: &str = "indexes";
+
+/// Optional global settings, every field has a default so the file
+/// only needs to contain what differs from it.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct Settings {
+    pub cache: CacheSettings,
+    pub redaction: RedactionSettings,
+    pub compaction: CompactionSettings,
+    /// short names usable in place of a model, e.g. `fast = {api = "groq", model = "llama3-70b-8192"}`
+    pub aliases: HashMap<String, ModelAlias>,
+}
+
+/// a model and optionally the api serving it, which the alias then switches to
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct ModelAlias {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api: Option<Api>,
+    pub model: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct CacheSettings {
+    /// opt-in, answers are stored on disk and reused for identical requests
+    pub enabled: bool,
+    /// how long an answer stays valid
+    pub ttl_seconds: u64,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        CacheSettings {
+            enabled: false,
+            ttl_seconds: 7 * 24 * 3600,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct CompactionSettings {
+    /// opt-in, summarize the older turns of a conversation extended with `-e` once it gets too long
+    pub enabled: bool,
+    /// size of the conversation, in chars, above which it's compacted
+    pub threshold_chars: usize,
+    /// exchanges kept as they are, the older ones are replaced by their summary
+    pub keep_last_turns: usize,
+}
+
+impl Default for CompactionSettings {
+    fn default() -> Self {
+        CompactionSettings {
+            enabled: false,
+            threshold_chars: 30_000,
+            keep_last_turns: 4,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct RedactionSettings {
+    /// opt-in, mask secrets and personal data before sending the prompt
+    pub enabled: bool,
+    pub emails: bool,
+    /// long random looking tokens, may catch hashes or encoded data as well
+    pub high_entropy: bool,
+    /// put the original values back in the answer
+    pub restore: bool,
+    /// additional regexes, only the first capture group is masked if there is one
+    pub patterns: Vec<String>,
+}
+
+impl Default for RedactionSettings {
+    fn default() -> Self {
+        RedactionSettings {
+            enabled: false,
+            emails: true,
+            high_entropy: true,
+            restore: false,
+            patterns: Vec::new(),
+        }
+    }
+}
+
+pub(super) fn settings_path() -> PathBuf {
+    resolve_config_path().join(SETTINGS_FILE)
+}
+
+pub fn cache_dir_path() -> PathBuf {
+    resolve_config_path().join(CACHE_DIR)
+}
+
+pub fn index_dir_path() -> PathBuf {
+    resolve_config_path().join(INDEX_DIR)
+}
+
+pub fn get_settings() -> Settings {
+    if !settings_path().exists() {
+        return Settings::default();
+    }
+    let content = fs::read_to_string(settings_path())
+        .unwrap_or_else(|error| panic!("Could not read file {:?}, {:?}", settings_path(), error));
+    toml::from_str(&content).expect("could not parse settings file content")
+}
@@ -0,0 +1,138 @@
+use glob::glob;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use log::debug;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::{ContextEntry, SkipReason};
+
+/// files bigger than this are never sent as context
+pub const MAX_CONTEXT_FILE_SIZE: u64 = 1_000_000;
+/// same heuristic as git, a NUL byte in the first few KB means binary
+const BINARY_DETECTION_WINDOW: usize = 8000;
+
+/// Expand the glob patterns into the content of the matching files.
+/// Files are deduplicated, ignored files (`.gitignore`, `.ignore`) are left out
+/// and binary or oversized files are reported as skipped.
+pub fn collect_files(glob_patterns: &[String]) -> (Vec<ContextEntry>, Vec<(PathBuf, SkipReason)>) {
+    let mut ignore_rules = IgnoreRules::default();
+    let mut seen = HashSet::new();
+    let mut entries = Vec::new();
+    let mut skipped = Vec::new();
+
+    let paths = glob_patterns.iter().flat_map(|glob_pattern| {
+        glob(glob_pattern)
+            .expect("Failed to read glob pattern")
+            .filter_map(Result::ok)
+    });
+
+    for path in paths {
+        if !path.is_file() {
+            continue;
+        }
+        let canonical_path = match path.canonicalize() {
+            Ok(canonical_path) => canonical_path,
+            Err(_) => {
+                skipped.push((path, SkipReason::Unreadable));
+                continue;
+            }
+        };
+        if !seen.insert(canonical_path.clone()) {
+            continue;
+        }
+        if ignore_rules.is_ignored(&canonical_path) {
+            debug!("ignored context file {}", path.display());
+            skipped.push((path, SkipReason::Ignored));
+            continue;
+        }
+        match read_text_file(&path) {
+            Ok(content) => entries.push(ContextEntry {
+                label: path.display().to_string(),
+                content,
+            }),
+            Err(reason) => skipped.push((path, reason)),
+        }
+    }
+
+    (entries, skipped)
+}
+
+fn read_text_file(path: &Path) -> Result<String, SkipReason> {
+    let size = fs::metadata(path)
+        .map_err(|_| SkipReason::Unreadable)?
+        .len();
+    if size > MAX_CONTEXT_FILE_SIZE {
+        return Err(SkipReason::TooLarge(size));
+    }
+    let bytes = fs::read(path).map_err(|_| SkipReason::Unreadable)?;
+    if bytes.iter().take(BINARY_DETECTION_WINDOW).any(|b| *b == 0) {
+        return Err(SkipReason::Binary);
+    }
+    String::from_utf8(bytes).map_err(|_| SkipReason::Binary)
+}
+
+/// Lazily loaded ignore files of every directory met while collecting files.
+/// `.gitignore` files are only honored inside a git repository, `.ignore` files everywhere.
+#[derive(Default)]
+struct IgnoreRules {
+    matchers_by_dir: HashMap<PathBuf, Vec<Gitignore>>,
+}
+
+impl IgnoreRules {
+    fn is_ignored(&mut self, path: &Path) -> bool {
+        if path
+            .components()
+            .any(|component| component.as_os_str() == ".git")
+        {
+            return true;
+        }
+        let repo_root = path
+            .ancestors()
+            .skip(1)
+            .find(|dir| dir.join(".git").exists())
+            .map(Path::to_path_buf);
+
+        for dir in path.ancestors().skip(1) {
+            let in_repo = repo_root.as_ref().is_some_and(|root| dir.starts_with(root));
+            let matchers = self
+                .matchers_by_dir
+                .entry(dir.to_path_buf())
+                .or_insert_with(|| {
+                    load_ignore_files(dir, in_repo, Some(dir) == repo_root.as_deref())
+                });
+            for matcher in matchers.iter() {
+                match matcher.matched_path_or_any_parents(path, false) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => {}
+                }
+            }
+        }
+        false
+    }
+}
+
+/// ignore files of a directory, ordered by precedence
+fn load_ignore_files(dir: &Path, in_repo: bool, is_repo_root: bool) -> Vec<Gitignore> {
+    let mut candidates = vec![dir.join(".ignore")];
+    if in_repo {
+        candidates.push(dir.join(".gitignore"));
+    }
+    if is_repo_root {
+        candidates.push(dir.join(".git").join("info").join("exclude"));
+    }
+
+    candidates
+        .into_iter()
+        .filter(|file| file.is_file())
+        .filter_map(|file| {
+            let mut builder = GitignoreBuilder::new(dir);
+            if let Some(error) = builder.add(&file) {
+                debug!("could not fully parse {}: {}", file.display(), error);
+            }
+            builder.build().ok()
+        })
+        .collect()
+}
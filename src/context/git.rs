@@ -0,0 +1,182 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use super::ContextEntry;
+
+const STAGED: &str = "staged";
+
+/// `git diff` output, against the index by default, `staged` for the staged
+/// changes or any ref/range git understands (e.g. `main`, `main...HEAD`)
+pub fn diff(target: Option<&str>) -> Option<ContextEntry> {
+    let mut args = vec!["diff"];
+    match target {
+        Some(STAGED) => args.push("--staged"),
+        // would be taken as an option, e.g. `--output=<file>` writes a file
+        Some(git_ref) if git_ref.starts_with('-') => {
+            eprintln!(
+                "`{}` isn't a git ref, nothing added to the context.",
+                git_ref
+            );
+            return None;
+        }
+        Some(git_ref) => args.push(git_ref),
+        None => {}
+    }
+    command_entry(Path::new("."), &args)
+}
+
+/// the last `count` commits with their full message
+pub fn log(count: u32) -> Option<ContextEntry> {
+    command_entry(Path::new("."), &["log", "-n", &count.to_string()])
+}
+
+/// Paths, relative to the current dir, of the tracked files changed since HEAD
+/// and of the untracked files that aren't ignored.
+pub fn changed_files() -> Vec<PathBuf> {
+    changed_files_in(Path::new(".")).unwrap_or_else(|e| {
+        eprintln!("{}, no changed file added to the context.", e);
+        Vec::new()
+    })
+}
+
+fn changed_files_in(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let repo_root = PathBuf::from(run_git(dir, &["rev-parse", "--show-cdup"])?.trim());
+    let tracked = run_git(dir, &["diff", "--name-only", "HEAD"])?;
+    let untracked = run_git(
+        dir,
+        &[
+            "ls-files",
+            "--others",
+            "--exclude-standard",
+            "--full-name",
+            ":/",
+        ],
+    )?;
+
+    Ok(tracked
+        .lines()
+        .chain(untracked.lines())
+        .filter(|line| !line.is_empty())
+        .map(|path| repo_root.join(path))
+        .collect())
+}
+
+fn command_entry(dir: &Path, args: &[&str]) -> Option<ContextEntry> {
+    let label = format!("git {}", args.join(" "));
+    let content = match run_git(dir, args) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("{}, nothing added to the context.", e);
+            return None;
+        }
+    };
+    if content.trim().is_empty() {
+        eprintln!("`{}` is empty, nothing added to the context.", label);
+        return None;
+    }
+    Some(ContextEntry { label, content })
+}
+
+/// the output of git run in `dir`, `Err` with what git said when it fails
+fn run_git(dir: &Path, args: &[&str]) -> Result<String, String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| {
+            format!(
+                "Failed to run git, make sure it's installed and in your PATH: {}",
+                e
+            )
+        })?;
+    if !output.status.success() {
+        return Err(format!(
+            "`git {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    fn repo() -> tempfile::TempDir {
+        let repo = tempfile::tempdir().unwrap();
+        git(repo.path(), &["init", "-q"]);
+        fs::write(repo.path().join("main.rs"), "fn main() {}\n").unwrap();
+        git(repo.path(), &["add", "main.rs"]);
+        git(repo.path(), &["commit", "-q", "-m", "add main"]);
+        repo
+    }
+
+    #[test]
+    fn test_command_entry() {
+        let repo = repo();
+        fs::write(repo.path().join("main.rs"), "fn main() {\n    run();\n}\n").unwrap();
+
+        let entry = command_entry(repo.path(), &["diff"]).unwrap();
+
+        assert_eq!(entry.label, "git diff");
+        assert!(entry.content.contains("+    run();"));
+        assert!(command_entry(repo.path(), &["diff", "--staged"]).is_none());
+    }
+
+    #[test]
+    fn test_failing_git_is_an_error() {
+        let repo = repo();
+
+        assert!(command_entry(repo.path(), &["diff", "no-such-ref"]).is_none());
+        assert!(run_git(repo.path(), &["diff", "no-such-ref"])
+            .unwrap_err()
+            .starts_with("`git diff no-such-ref` failed: "));
+
+        let not_a_repo = tempfile::tempdir().unwrap();
+        assert!(changed_files_in(not_a_repo.path()).is_err());
+    }
+
+    #[test]
+    fn test_diff_refuses_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_path = dir.path().join("diff");
+
+        assert!(diff(Some(&format!("--output={}", output_path.display()))).is_none());
+        assert!(!output_path.exists());
+    }
+
+    #[test]
+    fn test_changed_files() {
+        let repo = repo();
+        fs::write(repo.path().join("main.rs"), "fn main() { run() }\n").unwrap();
+        fs::create_dir(repo.path().join("src")).unwrap();
+        fs::write(repo.path().join("src/lib.rs"), "pub fn run() {}\n").unwrap();
+        fs::write(repo.path().join(".gitignore"), "*.log\n").unwrap();
+        fs::write(repo.path().join("build.log"), "noise").unwrap();
+
+        let mut files = changed_files_in(&repo.path().join("src")).unwrap();
+        files.sort();
+
+        assert_eq!(
+            files,
+            vec![
+                PathBuf::from("../.gitignore"),
+                PathBuf::from("../main.rs"),
+                PathBuf::from("../src/lib.rs")
+            ]
+        );
+    }
+}
@@ -0,0 +1,335 @@
+mod files;
+mod git;
+mod rag;
+
+use std::fmt;
+
+pub use self::files::collect_files;
+use self::files::MAX_CONTEXT_FILE_SIZE;
+use self::rag::DEFAULT_RAG_BUDGET;
+pub use self::rag::{chunk_lines, RagMode};
+use crate::PromptParams;
+
+/// a piece of context and the label under which it's presented to the model
+#[derive(Debug, PartialEq, Clone)]
+pub struct ContextEntry {
+    pub label: String,
+    pub content: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SkipReason {
+    Ignored,
+    Binary,
+    TooLarge(u64),
+    Unreadable,
+    BudgetExhausted,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SkipReason::Ignored => write!(f, "ignored"),
+            SkipReason::Binary => write!(f, "binary"),
+            SkipReason::TooLarge(size) => {
+                write!(f, "too large, {} > {} bytes", size, MAX_CONTEXT_FILE_SIZE)
+            }
+            SkipReason::Unreadable => write!(f, "unreadable"),
+            SkipReason::BudgetExhausted => write!(f, "context budget exhausted"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum EntryStatus {
+    Included,
+    Truncated { kept: usize, total: usize },
+    Skipped(SkipReason),
+}
+
+/// what ended up in the context message, used for `--dry-run`
+#[derive(Debug, PartialEq, Clone)]
+pub struct ReportEntry {
+    pub label: String,
+    pub status: EntryStatus,
+}
+
+#[derive(Debug, Default)]
+pub struct Context {
+    /// entries formatted for the context message, empty if nothing was collected
+    pub content: String,
+    pub report: Vec<ReportEntry>,
+    /// what the user should know about how the context was picked
+    pub warnings: Vec<String>,
+}
+
+/// Collect every context source requested in the params and pack it within the budget,
+/// with `--rag` only the chunks of the files most relevant to the query are kept,
+/// a dry run doesn't call any api for that
+pub fn gather_context(prompt_params: &PromptParams, query: &str, dry_run: bool) -> Context {
+    let mut entries = Vec::new();
+    if let Some(target) = &prompt_params.git_diff {
+        entries.extend(git::diff(target.as_deref()));
+    }
+    if let Some(count) = prompt_params.git_log {
+        entries.extend(git::log(count));
+    }
+
+    let mut patterns = prompt_params.context.clone();
+    if prompt_params.git_files_changed {
+        patterns.extend(
+            git::changed_files()
+                .iter()
+                .map(|path| glob::Pattern::escape(&path.to_string_lossy())),
+        );
+    }
+    let (file_entries, skipped) = collect_files(&patterns);
+    let mut warnings = Vec::new();
+    let budget = match prompt_params.rag {
+        Some(mode) => {
+            entries.extend(rag::select_chunks(
+                file_entries,
+                query,
+                mode,
+                dry_run,
+                &mut warnings,
+            ));
+            prompt_params.context_budget.or(Some(DEFAULT_RAG_BUDGET))
+        }
+        None => {
+            entries.extend(file_entries);
+            prompt_params.context_budget
+        }
+    };
+
+    let mut context = pack_entries(entries, budget);
+    context
+        .report
+        .extend(skipped.into_iter().map(|(path, reason)| ReportEntry {
+            label: path.display().to_string(),
+            status: EntryStatus::Skipped(reason),
+        }));
+    context.warnings = warnings;
+    context
+}
+
+/// Format the entries one after the other until the budget (in chars) runs out,
+/// the entry reaching the limit is truncated and marked as such.
+fn pack_entries(entries: Vec<ContextEntry>, budget: Option<usize>) -> Context {
+    let mut remaining = budget.filter(|budget| *budget > 0);
+    let mut context = Context::default();
+
+    for entry in entries {
+        let total = entry.content.chars().count();
+        let status = match remaining {
+            Some(0) => EntryStatus::Skipped(SkipReason::BudgetExhausted),
+            Some(left) if left < total => EntryStatus::Truncated { kept: left, total },
+            _ => EntryStatus::Included,
+        };
+
+        match status {
+            EntryStatus::Included => {
+                context
+                    .content
+                    .push_str(&format!("{}:\n```\n{}\n```\n", entry.label, entry.content));
+            }
+            EntryStatus::Truncated { kept, total } => {
+                let truncated_content: String = entry.content.chars().take(kept).collect();
+                context.content.push_str(&format!(
+                    "{} (truncated to {} of {} chars):\n```\n{}\n[...]\n```\n",
+                    entry.label, kept, total, truncated_content
+                ));
+            }
+            EntryStatus::Skipped(_) => {}
+        }
+
+        if let Some(left) = remaining.as_mut() {
+            *left = left.saturating_sub(total);
+        }
+        context.report.push(ReportEntry {
+            label: entry.label,
+            status,
+        });
+    }
+
+    context
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (sent, skipped): (Vec<_>, Vec<_>) = self
+            .report
+            .iter()
+            .partition(|entry| !matches!(entry.status, EntryStatus::Skipped(_)));
+
+        writeln!(
+            f,
+            "context included ({} chars):",
+            self.content.chars().count()
+        )?;
+        for entry in sent {
+            match &entry.status {
+                EntryStatus::Truncated { kept, total } => writeln!(
+                    f,
+                    "  {} (truncated to {} of {} chars)",
+                    entry.label, kept, total
+                )?,
+                _ => writeln!(f, "  {}", entry.label)?,
+            }
+        }
+
+        let ignored_count = skipped
+            .iter()
+            .filter(|entry| entry.status == EntryStatus::Skipped(SkipReason::Ignored))
+            .count();
+        if ignored_count > 0 {
+            writeln!(f, "{} ignored file(s) left out", ignored_count)?;
+        }
+        let other_skipped: Vec<_> = skipped
+            .iter()
+            .filter(|entry| entry.status != EntryStatus::Skipped(SkipReason::Ignored))
+            .collect();
+        if !other_skipped.is_empty() {
+            writeln!(f, "context skipped:")?;
+            for entry in other_skipped {
+                if let EntryStatus::Skipped(reason) = &entry.status {
+                    writeln!(f, "  {} ({})", entry.label, reason)?;
+                }
+            }
+        }
+        for warning in &self.warnings {
+            writeln!(f, "warning: {}", warning)?;
+        }
+        Ok(())
+    }
+}
+
+impl Context {
+    pub fn is_empty(&self) -> bool {
+        self.content.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    fn params_for(patterns: &[String], context_budget: Option<usize>) -> PromptParams {
+        PromptParams {
+            context: patterns.to_vec(),
+            context_budget,
+            ..PromptParams::default()
+        }
+    }
+
+    fn pattern(dir: &Path, glob: &str) -> String {
+        dir.join(glob).to_str().unwrap().to_owned()
+    }
+
+    fn included(context: &Context) -> Vec<String> {
+        context
+            .report
+            .iter()
+            .filter(|entry| !matches!(entry.status, EntryStatus::Skipped(_)))
+            .map(|entry| entry.label.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_gitignored_and_git_files_are_left_out() {
+        let repo = tempfile::TempDir::new().unwrap();
+        fs::create_dir_all(repo.path().join(".git")).unwrap();
+        fs::write(repo.path().join(".git/HEAD"), "ref: refs/heads/main").unwrap();
+        fs::create_dir_all(repo.path().join("target/debug")).unwrap();
+        fs::write(repo.path().join("target/debug/build.log"), "noise").unwrap();
+        fs::write(repo.path().join(".gitignore"), "target/\n*.lock\n").unwrap();
+        fs::write(repo.path().join("Cargo.lock"), "noise").unwrap();
+        fs::write(repo.path().join("main.rs"), "fn main() {}").unwrap();
+
+        let context = gather_context(
+            &params_for(&[pattern(repo.path(), "**/*")], None),
+            "",
+            false,
+        );
+
+        assert_eq!(
+            included(&context),
+            vec![
+                pattern(repo.path(), ".gitignore"),
+                pattern(repo.path(), "main.rs")
+            ]
+        );
+        assert!(!context.content.contains("noise"));
+    }
+
+    #[test]
+    fn test_binary_files_are_skipped() {
+        let dir = tempfile::TempDir::new().unwrap();
+        fs::write(dir.path().join("image.png"), [137, 80, 78, 71, 0, 0, 0, 13]).unwrap();
+        fs::write(dir.path().join("notes.txt"), "some notes").unwrap();
+
+        let context = gather_context(&params_for(&[pattern(dir.path(), "*")], None), "", false);
+
+        assert_eq!(included(&context), vec![pattern(dir.path(), "notes.txt")]);
+        assert!(context.report.contains(&ReportEntry {
+            label: pattern(dir.path(), "image.png"),
+            status: EntryStatus::Skipped(SkipReason::Binary),
+        }));
+    }
+
+    #[test]
+    fn test_files_matched_several_times_are_included_once() {
+        let dir = tempfile::TempDir::new().unwrap();
+        fs::write(dir.path().join("lib.rs"), "pub fn lib() {}").unwrap();
+
+        let context = gather_context(
+            &params_for(
+                &[pattern(dir.path(), "*.rs"), pattern(dir.path(), "lib.rs")],
+                None,
+            ),
+            "",
+            false,
+        );
+
+        assert_eq!(context.content.matches("pub fn lib() {}").count(), 1);
+    }
+
+    #[test]
+    fn test_budget_truncates_then_skips() {
+        let entries = vec![
+            ContextEntry {
+                label: "a".into(),
+                content: "aaaa".into(),
+            },
+            ContextEntry {
+                label: "b".into(),
+                content: "bbbb".into(),
+            },
+            ContextEntry {
+                label: "c".into(),
+                content: "cccc".into(),
+            },
+        ];
+
+        let context = pack_entries(entries, Some(6));
+
+        assert_eq!(
+            context.content,
+            "a:\n```\naaaa\n```\nb (truncated to 2 of 4 chars):\n```\nbb\n[...]\n```\n"
+        );
+        assert_eq!(
+            context
+                .report
+                .iter()
+                .map(|entry| entry.status.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                EntryStatus::Included,
+                EntryStatus::Truncated { kept: 2, total: 4 },
+                EntryStatus::Skipped(SkipReason::BudgetExhausted),
+            ]
+        );
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+
+use super::ContextEntry;
+use crate::config::prompt::get_prompts;
+use crate::embeddings;
+
+/// files are split in chunks of about this many chars, cut at line ends
+const CHUNK_CHARS: usize = 1500;
+/// chunks of a single long line are cut, the embedding models have a max input size
+const MAX_CHUNK_CHARS: usize = 4 * CHUNK_CHARS;
+/// how much context is packed when no budget is given, about 5k tokens
+pub const DEFAULT_RAG_BUDGET: usize = 20_000;
+/// usual bm25 parameters, term frequency saturation and length normalization
+const BM25_K1: f64 = 1.2;
+const BM25_B: f64 = 0.75;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum RagMode {
+    /// keyword ranking, local
+    Bm25,
+    /// similarity with the embeddings of the index made by `sc embed`, updated on the fly
+    Embeddings,
+}
+
+/// Replace the files by their chunks relevant to the query, most relevant first,
+/// labeled with their path and line range. A dry run ranks with bm25 to neither
+/// call the api nor touch the index, what the user should know ends up in `warnings`.
+pub fn select_chunks(
+    files: Vec<ContextEntry>,
+    query: &str,
+    mode: RagMode,
+    dry_run: bool,
+    warnings: &mut Vec<String>,
+) -> Vec<ContextEntry> {
+    let chunks: Vec<(String, (usize, usize, String))> = files
+        .iter()
+        .flat_map(|file| {
+            chunk_lines(&file.content)
+                .into_iter()
+                .map(|chunk| (file.label.clone(), chunk))
+        })
+        .collect();
+
+    let scores = match mode {
+        RagMode::Embeddings if dry_run => {
+            warnings.push(
+                "dry run, the context is ranked with bm25 instead of the embeddings".to_string(),
+            );
+            bm25_scores(&chunks, query)
+        }
+        RagMode::Embeddings => embeddings_scores(&files, &chunks, query).unwrap_or_else(|e| {
+            warnings.push(format!("{}, ranking the context with bm25 instead", e));
+            bm25_scores(&chunks, query)
+        }),
+        RagMode::Bm25 => bm25_scores(&chunks, query),
+    };
+
+    let chunk_count = chunks.len();
+    let mut ranked: Vec<_> = chunks
+        .into_iter()
+        .zip(scores)
+        .filter(|(_, score)| *score > 0.0)
+        .collect();
+    if ranked.is_empty() && chunk_count > 0 {
+        warnings.push(format!(
+            "none of the {} chunk(s) of the context files matches the query, no context is sent",
+            chunk_count
+        ));
+    }
+    ranked.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    ranked
+        .into_iter()
+        .map(|((path, (start_line, end_line, text)), _)| ContextEntry {
+            label: format!("{} (lines {}-{})", path, start_line, end_line),
+            content: text.trim_end().to_string(),
+        })
+        .collect()
+}
+
+/// Split the content in chunks of whole lines of about `CHUNK_CHARS` chars,
+/// as (first line, last line, text) with lines numbered from 1.
+pub fn chunk_lines(content: &str) -> Vec<(usize, usize, String)> {
+    let mut chunks = Vec::new();
+    let mut text = String::new();
+    let mut start_line = 1;
+    for (line_index, line) in content.lines().enumerate() {
+        text.push_str(line);
+        text.push('\n');
+        if text.len() >= CHUNK_CHARS {
+            chunks.push((start_line, line_index + 1, truncate(text)));
+            text = String::new();
+            start_line = line_index + 2;
+        }
+    }
+    if !text.trim().is_empty() {
+        chunks.push((start_line, content.lines().count(), truncate(text)));
+    }
+    chunks
+}
+
+fn truncate(text: String) -> String {
+    match text.char_indices().nth(MAX_CHUNK_CHARS) {
+        Some((byte_index, _)) => text[..byte_index].to_string(),
+        None => text,
+    }
+}
+
+/// lowercase words, identifiers are split on underscores
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn bm25_scores(chunks: &[(String, (usize, usize, String))], query: &str) -> Vec<f64> {
+    let documents: Vec<Vec<String>> = chunks
+        .iter()
+        .map(|(_, (_, _, text))| tokenize(text))
+        .collect();
+    if documents.is_empty() {
+        return Vec::new();
+    }
+    let average_length =
+        documents.iter().map(Vec::len).sum::<usize>() as f64 / documents.len() as f64;
+
+    let mut query_terms = tokenize(query);
+    query_terms.sort();
+    query_terms.dedup();
+    let document_frequencies: HashMap<&str, usize> = query_terms
+        .iter()
+        .map(|term| {
+            let count = documents
+                .iter()
+                .filter(|document| document.contains(term))
+                .count();
+            (term.as_str(), count)
+        })
+        .collect();
+
+    documents
+        .iter()
+        .map(|document| {
+            query_terms
+                .iter()
+                .map(|term| {
+                    let frequency = document.iter().filter(|token| *token == term).count() as f64;
+                    if frequency == 0.0 {
+                        return 0.0;
+                    }
+                    let document_frequency = document_frequencies[term.as_str()] as f64;
+                    let idf = ((documents.len() as f64 - document_frequency + 0.5)
+                        / (document_frequency + 0.5)
+                        + 1.0)
+                        .ln();
+                    let length_norm =
+                        1.0 - BM25_B + BM25_B * document.len() as f64 / average_length.max(1.0);
+                    idf * frequency * (BM25_K1 + 1.0) / (frequency + BM25_K1 * length_norm)
+                })
+                .sum()
+        })
+        .collect()
+}
+
+/// cosine similarity of each chunk with the query, the index is updated with the files first
+fn embeddings_scores(
+    files: &[ContextEntry],
+    chunks: &[(String, (usize, usize, String))],
+    query: &str,
+) -> Result<Vec<f64>, crate::utils::ApiError> {
+    let patterns: Vec<String> = files
+        .iter()
+        .map(|file| glob::Pattern::escape(&file.label))
+        .collect();
+    let index_path = embeddings::project_index_path();
+    let index_path = index_path.as_path();
+    // keep the model of an existing index, the api of the default prompt otherwise
+    let (api, model) = match embeddings::index_model(index_path) {
+        Some((api, model)) => (api, Some(model)),
+        None => (
+            get_prompts().remove("default").unwrap_or_default().api,
+            None,
+        ),
+    };
+    embeddings::embed(&patterns, api, model, index_path)?;
+    let scores = embeddings::chunk_scores(query, index_path)?;
+    Ok(chunks
+        .iter()
+        .map(|(path, (start_line, _, _))| {
+            scores
+                .get(&(path.clone(), *start_line))
+                .copied()
+                .unwrap_or_default() as f64
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_lines_cuts_at_line_ends() {
+        let line = "x".repeat(999);
+        let content = format!("{}\n{}\n{}\n", line, line, line);
+
+        let chunks = chunk_lines(&content);
+
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|(start, end, text)| (*start, *end, text.len()))
+                .collect::<Vec<_>>(),
+            vec![(1, 2, 2000), (3, 3, 1000)]
+        );
+    }
+
+    #[test]
+    fn test_bm25_selects_relevant_chunks() {
+        let files = vec![
+            ContextEntry {
+                label: "src/auth.rs".to_string(),
+                content: "fn check_password(user: &User, password: &str) -> bool".to_string(),
+            },
+            ContextEntry {
+                label: "src/config.rs".to_string(),
+                content: "fn load_config(path: &Path) -> Config".to_string(),
+            },
+            ContextEntry {
+                label: "README.md".to_string(),
+                content: "passwords are stored hashed, see each user settings".to_string(),
+            },
+        ];
+
+        let mut warnings = Vec::new();
+
+        let chunks = select_chunks(
+            files,
+            "where is the user password checked?",
+            RagMode::Bm25,
+            false,
+            &mut warnings,
+        );
+
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|chunk| chunk.label.as_str())
+                .collect::<Vec<_>>(),
+            vec!["src/auth.rs (lines 1-1)", "README.md (lines 1-1)"]
+        );
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_no_matching_chunk_is_warned() {
+        let files = vec![ContextEntry {
+            label: "src/config.rs".to_string(),
+            content: "fn load_config(path: &Path) -> Config".to_string(),
+        }];
+        let mut warnings = Vec::new();
+
+        let chunks = select_chunks(files, "password", RagMode::Bm25, false, &mut warnings);
+
+        assert!(chunks.is_empty());
+        assert_eq!(
+            warnings,
+            vec![
+                "none of the 1 chunk(s) of the context files matches the query, no context is sent"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dry_run_ranks_embeddings_with_bm25() {
+        let files = vec![ContextEntry {
+            label: "src/auth.rs".to_string(),
+            content: "fn check_password(user: &User, password: &str) -> bool".to_string(),
+        }];
+        let mut warnings = Vec::new();
+
+        let chunks = select_chunks(files, "password", RagMode::Embeddings, true, &mut warnings);
+
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(
+            warnings,
+            vec!["dry run, the context is ranked with bm25 instead of the embeddings"]
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::config::api::{Api, ApiConfig};
+use crate::utils::{handle_api_response, sibling_url, ApiError};
+
+/// openai, mistral, groq and cerebras
+#[derive(Debug, Deserialize)]
+struct OpenAiEmbeddings {
+    data: Vec<OpenAiEmbedding>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiEmbedding {
+    embedding: Vec<f32>,
+    index: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaEmbeddings {
+    embeddings: Vec<Vec<f32>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GeminiEmbeddings {
+    embeddings: Vec<GeminiEmbedding>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GeminiEmbedding {
+    values: Vec<f32>,
+}
+
+/// Embed the texts with the embeddings endpoint of the api, one vector per text in the same order.
+pub fn embed_texts(
+    api: &Api,
+    api_config: &ApiConfig,
+    model: &str,
+    texts: &[String],
+) -> Result<Vec<Vec<f32>>, ApiError> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(
+            api_config
+                .timeout_seconds
+                .map(|t| Duration::from_secs(t.into())),
+        )
+        .build()
+        .expect("Unable to initialize HTTP client");
+
+    let embeddings = match api {
+        Api::Openai | Api::Mistral | Api::Groq | Api::Cerebras => {
+            let request = client
+                .post(sibling_url(
+                    &api_config.url,
+                    "chat/completions",
+                    "embeddings",
+                ))
+                .header(
+                    "Authorization",
+                    format!("Bearer {}", api_config.get_api_key()),
+                )
+                .json(&serde_json::json!({ "model": model, "input": texts }));
+            let mut data = handle_api_response::<OpenAiEmbeddings>(request.send()?)?.data;
+            data.sort_by_key(|embedding| embedding.index);
+            data.into_iter()
+                .map(|embedding| embedding.embedding)
+                .collect()
+        }
+        Api::Ollama => {
+            let request = client
+                .post(sibling_url(&api_config.url, "api/chat", "api/embed"))
+                .json(&serde_json::json!({ "model": model, "input": texts }));
+            handle_api_response::<OllamaEmbeddings>(request.send()?)?.embeddings
+        }
+        Api::Gemini => {
+            let requests: Vec<_> = texts
+                .iter()
+                .map(|text| {
+                    serde_json::json!({
+                        "model": format!("models/{}", model),
+                        "content": { "parts": [{ "text": text }] },
+                    })
+                })
+                .collect();
+            let request = client
+                .post(format!(
+                    "{}/{}:batchEmbedContents",
+                    api_config.url.trim_end_matches('/'),
+                    model
+                ))
+                .header("x-goog-api-key", api_config.get_api_key())
+                .json(&serde_json::json!({ "requests": requests }));
+            handle_api_response::<GeminiEmbeddings>(request.send()?)?
+                .embeddings
+                .into_iter()
+                .map(|embedding| embedding.values)
+                .collect()
+        }
+        v => {
+            return Err(ApiError::Config(format!(
+                "Embeddings are not supported for {}, use one among {:?}",
+                v,
+                vec![
+                    Api::Openai,
+                    Api::Mistral,
+                    Api::Groq,
+                    Api::Cerebras,
+                    Api::Ollama,
+                    Api::Gemini
+                ]
+            )))
+        }
+    };
+    Ok(embeddings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unsupported_api_is_an_error() {
+        let result = embed_texts(
+            &Api::Anthropic,
+            &ApiConfig::default(),
+            "model",
+            &["text".to_string()],
+        );
+
+        assert!(matches!(
+            result,
+            Err(ApiError::Config(message))
+                if message.starts_with("Embeddings are not supported for anthropic")
+                    && message.contains("Groq")
+                    && message.contains("Cerebras")
+        ));
+    }
+}
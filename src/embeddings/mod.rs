@@ -0,0 +1,364 @@
+mod api_call;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use self::api_call::embed_texts;
+use crate::config::{
+    api::{get_api_configs, Api, ApiConfig},
+    settings::{get_settings, index_dir_path},
+};
+use crate::context::{chunk_lines, collect_files};
+use crate::text::redaction::{Redactions, Redactor};
+use crate::utils::ApiError;
+
+/// chunks embedded per request
+const BATCH_SIZE: usize = 64;
+
+/// The embeddings of the chunks of every indexed file, all made with the same model.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Index {
+    pub api: Api,
+    pub model: String,
+    /// by path, as given when embedding
+    pub files: BTreeMap<String, IndexedFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IndexedFile {
+    /// sha256 of the content, unchanged files aren't embedded again
+    pub hash: String,
+    pub chunks: Vec<Chunk>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Chunk {
+    pub start_line: usize,
+    pub end_line: usize,
+    pub text: String,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SearchResult {
+    pub path: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub score: f32,
+}
+
+#[derive(Debug, Default)]
+pub struct EmbedReport {
+    pub embedded_files: usize,
+    pub unchanged_files: usize,
+    pub embedded_chunks: usize,
+    pub skipped: Vec<PathBuf>,
+}
+
+/// The index of the current directory, kept in the config dir under the hash of its path
+/// so that the project itself is left untouched.
+pub fn project_index_path() -> PathBuf {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let hash: String = Sha256::digest(cwd.to_string_lossy().as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    index_dir_path().join(format!("{}.json", hash))
+}
+
+impl Index {
+    fn load(index_path: &Path) -> Option<Index> {
+        let content = fs::read_to_string(index_path).ok()?;
+        Some(serde_json::from_str(&content).unwrap_or_else(|error| {
+            panic!("Could not parse the index {:?}, {:?}", index_path, error)
+        }))
+    }
+
+    fn save(&self, index_path: &Path) -> std::io::Result<()> {
+        if let Some(parent) = index_path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(index_path, serde_json::to_string(self)?)
+    }
+}
+
+/// Embed the chunks of the files matching the patterns and add them to the index.
+/// Files already indexed and unchanged are kept as is, the index is rebuilt
+/// if it was made with another api or model.
+pub fn embed(
+    glob_patterns: &[String],
+    api: Api,
+    model: Option<String>,
+    index_path: &Path,
+) -> Result<EmbedReport, ApiError> {
+    let api_config = configured_api(&api)?;
+    let model = model
+        .or(api_config.embedding_model.clone())
+        .ok_or_else(|| {
+            ApiError::Config(
+                "embedding model must be specified either in the api config or with --model"
+                    .to_string(),
+            )
+        })?;
+
+    let mut index = match Index::load(index_path) {
+        Some(index) if index.api == api && index.model == model => index,
+        Some(index) => {
+            eprintln!(
+                "The index was made with {} ({}), rebuilding it with {} ({}).",
+                index.api, index.model, api, model
+            );
+            Index {
+                api,
+                model,
+                files: BTreeMap::new(),
+            }
+        }
+        None => Index {
+            api,
+            model,
+            files: BTreeMap::new(),
+        },
+    };
+    // forget the files which don't exist anymore
+    index.files.retain(|path, _| Path::new(path).is_file());
+
+    // what's sent is masked the same way as the prompts
+    let redaction_settings = get_settings().redaction;
+    let redactor = redaction_settings
+        .enabled
+        .then(|| Redactor::new(&redaction_settings));
+
+    let index_canonical_path = index_path.canonicalize().ok();
+    let (entries, skipped) = collect_files(glob_patterns);
+    let mut report = EmbedReport {
+        skipped: skipped.into_iter().map(|(path, _)| path).collect(),
+        ..EmbedReport::default()
+    };
+
+    // (path, hash, chunks to embed)
+    let mut pending = Vec::new();
+    for entry in entries {
+        if Path::new(&entry.label).canonicalize().ok() == index_canonical_path {
+            continue;
+        }
+        let hash: String = Sha256::digest(entry.content.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        if index
+            .files
+            .get(&entry.label)
+            .is_some_and(|file| file.hash == hash)
+        {
+            report.unchanged_files += 1;
+            continue;
+        }
+        let mut chunks = chunk_lines(&entry.content);
+        if let Some(redactor) = &redactor {
+            for (_, _, text) in chunks.iter_mut() {
+                *text = redactor.redact(text, &mut Redactions::default());
+            }
+        }
+        pending.push((entry.label, hash, chunks));
+    }
+
+    let total_chunks: usize = pending.iter().map(|(_, _, chunks)| chunks.len()).sum();
+    let texts: Vec<String> = pending
+        .iter()
+        .flat_map(|(_, _, chunks)| chunks.iter().map(|(_, _, text)| text.clone()))
+        .collect();
+    let mut embeddings = Vec::with_capacity(total_chunks);
+    for batch in texts.chunks(BATCH_SIZE) {
+        embeddings.extend(embed_texts(&index.api, &api_config, &index.model, batch)?);
+        eprint!("\rembedded {}/{} chunks", embeddings.len(), total_chunks);
+        let _ = std::io::stderr().flush();
+    }
+    if total_chunks > 0 {
+        eprintln!();
+    }
+
+    let mut embeddings = embeddings.into_iter();
+    for (path, hash, chunks) in pending {
+        let chunks = chunks
+            .into_iter()
+            .zip(embeddings.by_ref())
+            .map(|((start_line, end_line, text), embedding)| Chunk {
+                start_line,
+                end_line,
+                text,
+                embedding,
+            })
+            .collect();
+        index.files.insert(path, IndexedFile { hash, chunks });
+        report.embedded_files += 1;
+    }
+    report.embedded_chunks = total_chunks;
+
+    index
+        .save(index_path)
+        .unwrap_or_else(|error| panic!("Could not write the index {:?}, {:?}", index_path, error));
+    Ok(report)
+}
+
+/// The `top_k` chunks of the index closest to the query.
+pub fn search(query: &str, top_k: usize, index_path: &Path) -> Result<Vec<SearchResult>, ApiError> {
+    let index = Index::load(index_path).unwrap_or_else(|| {
+        panic!(
+            "No index found at {:?}, run `sc embed <files>` first",
+            index_path
+        )
+    });
+    let api_config = configured_api(&index.api)?;
+    let query_embedding = embed_texts(&index.api, &api_config, &index.model, &[query.to_string()])?
+        .pop()
+        .unwrap_or_default();
+    Ok(rank(&index, &query_embedding, top_k))
+}
+
+fn configured_api(api: &Api) -> Result<ApiConfig, ApiError> {
+    get_api_configs().remove(&api.to_string()).ok_or_else(|| {
+        ApiError::Config(format!("`{}` isn't configured in the api config file", api))
+    })
+}
+
+/// api and model the index was made with, if there is one
+pub fn index_model(index_path: &Path) -> Option<(Api, String)> {
+    Index::load(index_path).map(|index| (index.api, index.model))
+}
+
+/// Similarity of every chunk of the index with the query, by path and first line.
+pub fn chunk_scores(
+    query: &str,
+    index_path: &Path,
+) -> Result<HashMap<(String, usize), f32>, ApiError> {
+    Ok(search(query, usize::MAX, index_path)?
+        .into_iter()
+        .map(|result| ((result.path, result.start_line), result.score))
+        .collect())
+}
+
+/// paths of the results, without duplicates, best first
+pub fn result_files(results: &[SearchResult]) -> Vec<&str> {
+    let mut seen = HashSet::new();
+    results
+        .iter()
+        .map(|result| result.path.as_str())
+        .filter(|path| seen.insert(*path))
+        .collect()
+}
+
+fn rank(index: &Index, query_embedding: &[f32], top_k: usize) -> Vec<SearchResult> {
+    let mut results: Vec<SearchResult> = index
+        .files
+        .iter()
+        .flat_map(|(path, file)| {
+            file.chunks.iter().map(move |chunk| SearchResult {
+                path: path.clone(),
+                start_line: chunk.start_line,
+                end_line: chunk.end_line,
+                score: cosine_similarity(query_embedding, &chunk.embedding),
+            })
+        })
+        .collect();
+    results.sort_by(|a, b| b.score.total_cmp(&a.score));
+    results.truncate(top_k);
+    results
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[test]
+    fn test_rank_by_cosine_similarity() {
+        let chunk = |start_line, embedding| Chunk {
+            start_line,
+            end_line: start_line,
+            text: String::new(),
+            embedding,
+        };
+        let index = Index {
+            api: Api::Ollama,
+            model: "model".to_string(),
+            files: BTreeMap::from([
+                (
+                    "a.rs".to_string(),
+                    IndexedFile {
+                        hash: String::new(),
+                        chunks: vec![chunk(1, vec![1.0, 0.0]), chunk(10, vec![0.0, 1.0])],
+                    },
+                ),
+                (
+                    "b.rs".to_string(),
+                    IndexedFile {
+                        hash: String::new(),
+                        chunks: vec![chunk(1, vec![0.6, 0.8])],
+                    },
+                ),
+            ]),
+        };
+
+        let results = rank(&index, &[0.0, 2.0], 2);
+
+        assert_eq!(
+            results
+                .iter()
+                .map(|result| (result.path.as_str(), result.start_line))
+                .collect::<Vec<_>>(),
+            vec![("a.rs", 10), ("b.rs", 1)]
+        );
+        assert!((results[1].score - 0.8).abs() < 1e-6);
+        assert_eq!(result_files(&results), vec!["a.rs", "b.rs"]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_embed_without_model_or_config_is_an_error() -> std::io::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let original_value = std::env::var("SMARTCAT_CONFIG_PATH");
+        std::env::set_var("SMARTCAT_CONFIG_PATH", temp_dir.path());
+        fs::write(
+            temp_dir.path().join(".api_configs.toml"),
+            "[ollama]\nurl = \"http://127.0.0.1:1/api/chat\"\n",
+        )?;
+        let index_path = temp_dir.path().join("index.json");
+
+        let without_model = embed(&[], Api::Ollama, None, &index_path);
+        let without_config = embed(&[], Api::Openai, None, &index_path);
+
+        match original_value {
+            Ok(val) => std::env::set_var("SMARTCAT_CONFIG_PATH", val),
+            Err(_) => std::env::remove_var("SMARTCAT_CONFIG_PATH"),
+        }
+
+        assert!(matches!(
+            without_model,
+            Err(ApiError::Config(message)) if message.contains("embedding model")
+        ));
+        assert!(matches!(
+            without_config,
+            Err(ApiError::Config(message)) if message == "`openai` isn't configured in the api config file"
+        ));
+        assert!(!index_path.exists());
+        Ok(())
+    }
+}
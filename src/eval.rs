@@ -0,0 +1,615 @@
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::config::{
+    api::Api,
+    prompt::{get_prompts, Message, Prompt},
+    settings::get_settings,
+};
+use crate::prompt_customization::{customize_prompt, insert_vars};
+use crate::text::{cache::ResponseCache, compare::parse_backend, insert_input, request_answer};
+use crate::PromptParams;
+
+const GRADER_INSTRUCTIONS: &str = "You grade the answers of another model against a rubric. \
+    Reply with PASS if the answer satisfies every point of the rubric, FAIL otherwise, \
+    followed by a one sentence justification.";
+
+/// test cases of an eval file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EvalFile {
+    /// `api:model` grading the rubrics, the backend of each case otherwise
+    #[serde(default)]
+    grader: Option<String>,
+    #[serde(default, rename = "case")]
+    cases: Vec<EvalCase>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EvalCase {
+    name: String,
+    #[serde(default = "default_template")]
+    template: String,
+    #[serde(default)]
+    input: Option<String>,
+    /// relative to the eval file
+    #[serde(default)]
+    input_file: Option<PathBuf>,
+    /// as the second arg of `sc <template> <instruction>`
+    #[serde(default)]
+    instruction: Option<String>,
+    /// values replacing the `#[<name>]` placeholders of the template
+    #[serde(default)]
+    vars: HashMap<String, String>,
+    #[serde(default)]
+    api: Option<Api>,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    temperature: Option<f32>,
+    #[serde(default, rename = "assert")]
+    assertions: Vec<Assertion>,
+    /// compare the output to the one stored on the first run
+    #[serde(default)]
+    snapshot: bool,
+}
+
+fn default_template() -> String {
+    "default".to_string()
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum Assertion {
+    Contains(String),
+    NotContains(String),
+    Regex(String),
+    /// max number of chars
+    MaxLength(usize),
+    /// the output is json matching this schema, a subset of json schema is supported
+    JsonSchema(Value),
+    /// graded by a model
+    Rubric(String),
+}
+
+#[derive(Debug)]
+pub struct CaseResult {
+    pub name: String,
+    pub duration: Duration,
+    /// why the case failed, empty if it passed
+    pub failures: Vec<String>,
+    pub snapshot_written: bool,
+}
+
+impl CaseResult {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct EvalOptions {
+    /// only run the cases whose name contains it
+    pub filter: Option<String>,
+    pub update_snapshots: bool,
+    /// `<eval file stem>.snapshots` next to the eval file otherwise
+    pub snapshots_dir: Option<PathBuf>,
+}
+
+/// Run the cases of the eval file through the templates, the cache is not used so that
+/// changes of the models show up.
+pub fn run(
+    path: &Path,
+    options: &EvalOptions,
+    mut on_result: impl FnMut(&CaseResult),
+) -> Result<Vec<CaseResult>, Box<dyn std::error::Error>> {
+    let content = fs::read_to_string(path)
+        .map_err(|error| format!("Could not read file {:?}, {}", path, error))?;
+    let eval_file: EvalFile = toml::from_str(&content)
+        .map_err(|error| format!("Invalid eval file {:?}, {}", path, error))?;
+    check_snapshot_names(&eval_file.cases)
+        .map_err(|error| format!("Invalid eval file {:?}, {}", path, error))?;
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+    let snapshots_dir = options.snapshots_dir.clone().unwrap_or_else(|| {
+        base_dir.join(format!(
+            "{}.snapshots",
+            path.file_stem().unwrap_or_default().to_string_lossy()
+        ))
+    });
+
+    let prompts = get_prompts();
+    let cache = ResponseCache::new(&get_settings().cache, true, false);
+    let mut results = Vec::new();
+    for case in eval_file.cases.iter().filter(|case| {
+        options
+            .filter
+            .as_ref()
+            .is_none_or(|filter| case.name.contains(filter.as_str()))
+    }) {
+        let start = Instant::now();
+        let mut failures = Vec::new();
+        let mut snapshot_written = false;
+        let answer = prompts
+            .get(&case.template)
+            .cloned()
+            .ok_or(format!("no `{}` prompt template", case.template))
+            .and_then(|template| run_case(case, template, base_dir, &cache));
+        match answer {
+            Ok((prompt, output)) => {
+                for assertion in &case.assertions {
+                    if let Err(failure) = check(
+                        assertion,
+                        &output,
+                        &prompt,
+                        eval_file.grader.as_deref(),
+                        &cache,
+                    ) {
+                        failures.push(failure);
+                    }
+                }
+                if case.snapshot {
+                    let snapshot_path = snapshots_dir.join(snapshot_file_name(&case.name));
+                    match check_snapshot(&snapshot_path, &output, options.update_snapshots) {
+                        Ok(written) => snapshot_written = written,
+                        Err(failure) => failures.push(failure),
+                    }
+                }
+            }
+            Err(error) => failures.push(error),
+        }
+        let result = CaseResult {
+            name: case.name.clone(),
+            duration: start.elapsed(),
+            failures,
+            snapshot_written,
+        };
+        on_result(&result);
+        results.push(result);
+    }
+    Ok(results)
+}
+
+/// the case name with the chars not allowed in a file name replaced, `.txt` appended
+fn snapshot_file_name(case_name: &str) -> String {
+    let name: String = case_name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    format!("{}.txt", name)
+}
+
+/// Cases whose names give the same snapshot file would overwrite each other's snapshot,
+/// compared ignoring the case for the filesystems which do.
+fn check_snapshot_names(cases: &[EvalCase]) -> Result<(), String> {
+    let mut names = HashMap::new();
+    for case in cases.iter().filter(|case| case.snapshot) {
+        let file_name = snapshot_file_name(&case.name);
+        if let Some(other) = names.insert(file_name.to_lowercase(), &case.name) {
+            return Err(format!(
+                "the cases `{}` and `{}` would share the snapshot file `{}`, rename one of them",
+                other, case.name, file_name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Compare the output to the stored snapshot, or write it when there's none yet or it's
+/// updated. Returns whether it was written, `Err` with the reason when the case fails.
+fn check_snapshot(snapshot_path: &Path, output: &str, update: bool) -> Result<bool, String> {
+    match fs::read_to_string(snapshot_path) {
+        Ok(snapshot) if !update => {
+            if snapshot == output {
+                Ok(false)
+            } else {
+                Err(format!(
+                    "the output differs from the snapshot:\n{}",
+                    line_diff(&snapshot, output)
+                ))
+            }
+        }
+        _ => {
+            if let Some(snapshots_dir) = snapshot_path.parent() {
+                fs::create_dir_all(snapshots_dir)
+                    .map_err(|error| format!("could not create {:?}, {}", snapshots_dir, error))?;
+            }
+            fs::write(snapshot_path, output).map_err(|error| {
+                format!(
+                    "could not write the snapshot {:?}, {}",
+                    snapshot_path, error
+                )
+            })?;
+            Ok(true)
+        }
+    }
+}
+
+/// customize the template as `sc` does and get the answer, returns the prompt sent and the output
+fn run_case(
+    case: &EvalCase,
+    template: Prompt,
+    base_dir: &Path,
+    cache: &ResponseCache,
+) -> Result<(Prompt, String), String> {
+    let mut input = match (&case.input, &case.input_file) {
+        (Some(input), _) => input.clone(),
+        (None, Some(input_file)) => fs::read_to_string(base_dir.join(input_file))
+            .map_err(|error| format!("could not read {:?}, {}", input_file, error))?,
+        (None, None) => String::new(),
+    };
+    let mut instruction = case.instruction.clone();
+    if input.is_empty() {
+        input = instruction.take().unwrap_or_default();
+    }
+
+    let prompt_params = PromptParams {
+        api: case.api.clone(),
+        model: case.model.clone(),
+        temperature: case.temperature,
+        ..PromptParams::default()
+    };
+    let rag_query = instruction.clone().unwrap_or_else(|| input.clone());
+    let mut prompt = customize_prompt(template, &prompt_params, instruction, &rag_query);
+    prompt.resolve_aliases(&get_settings().aliases, prompt_params.api.as_ref());
+    insert_vars(&mut prompt.messages, &case.vars);
+
+    let redactions = insert_input(&mut prompt, &input, false);
+    let answer = request_answer(&prompt, cache).map_err(|e| e.to_string())?;
+    let output = if get_settings().redaction.restore {
+        redactions.restore(&answer.content)
+    } else {
+        answer.content
+    };
+    Ok((prompt, output))
+}
+
+/// `Err` with the reason when the output doesn't satisfy the assertion
+fn check(
+    assertion: &Assertion,
+    output: &str,
+    prompt: &Prompt,
+    grader: Option<&str>,
+    cache: &ResponseCache,
+) -> Result<(), String> {
+    match assertion {
+        Assertion::Contains(text) if !output.contains(text.as_str()) => {
+            Err(format!("expected the output to contain {:?}", text))
+        }
+        Assertion::NotContains(text) if output.contains(text.as_str()) => {
+            Err(format!("expected the output not to contain {:?}", text))
+        }
+        Assertion::Regex(pattern) => {
+            let regex = Regex::new(pattern)
+                .map_err(|error| format!("invalid regex {:?}, {}", pattern, error))?;
+            if regex.is_match(output) {
+                Ok(())
+            } else {
+                Err(format!("expected the output to match /{}/", pattern))
+            }
+        }
+        Assertion::MaxLength(max_length) if output.chars().count() > *max_length => Err(format!(
+            "expected at most {} chars, got {}",
+            max_length,
+            output.chars().count()
+        )),
+        Assertion::JsonSchema(schema) => {
+            let value: Value = serde_json::from_str(output.trim())
+                .map_err(|error| format!("expected json output, {}", error))?;
+            let errors = schema_errors(&value, schema, "$");
+            if errors.is_empty() {
+                Ok(())
+            } else {
+                Err(format!(
+                    "the output doesn't match the schema: {}",
+                    errors.join(", ")
+                ))
+            }
+        }
+        Assertion::Rubric(rubric) => grade(rubric, output, prompt, grader, cache),
+        _ => Ok(()),
+    }
+}
+
+/// ask the grader, the model of the case otherwise, whether the output satisfies the rubric
+fn grade(
+    rubric: &str,
+    output: &str,
+    prompt: &Prompt,
+    grader: Option<&str>,
+    cache: &ResponseCache,
+) -> Result<(), String> {
+    let mut grader_prompt = Prompt {
+        api: prompt.api.clone(),
+        model: prompt.model.clone(),
+        messages: vec![
+            Message::system(GRADER_INSTRUCTIONS),
+            Message::user(&format!("Rubric:\n{}\n\nAnswer:\n{}", rubric, output)),
+        ],
+        temperature: Some(0.),
+        ..Prompt::empty()
+    };
+    if let Some(grader) = grader {
+        let backend = parse_backend(grader, &prompt.api, &get_settings().aliases)?;
+        grader_prompt.api = backend.api;
+        grader_prompt.model = backend.model;
+    }
+    let verdict = request_answer(&grader_prompt, cache)
+        .map_err(|e| format!("the rubric could not be graded, {}", e))?
+        .content;
+    let verdict = verdict.trim();
+    if verdict.to_uppercase().starts_with("PASS") {
+        Ok(())
+    } else {
+        Err(format!("rubric {:?} not satisfied: {}", rubric, verdict))
+    }
+}
+
+/// Validate the `type`, `enum`, `required`, `properties`, `additionalProperties: false`
+/// and `items` keywords of the schema, the other ones are ignored.
+fn schema_errors(value: &Value, schema: &Value, path: &str) -> Vec<String> {
+    let mut errors = Vec::new();
+    if let Some(expected) = schema.get("type").and_then(Value::as_str) {
+        let matches = match expected {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "number" => value.is_number(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "boolean" => value.is_boolean(),
+            "null" => value.is_null(),
+            _ => true,
+        };
+        if !matches {
+            return vec![format!("{} is not of type {}", path, expected)];
+        }
+    }
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(value) {
+            errors.push(format!(
+                "{} is not one of {}",
+                path,
+                Value::from(allowed.clone())
+            ));
+        }
+    }
+    if let Some(object) = value.as_object() {
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for required in schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+        {
+            if !object.contains_key(required) {
+                errors.push(format!("{}.{} is missing", path, required));
+            }
+        }
+        for (key, property) in object {
+            match properties.and_then(|properties| properties.get(key)) {
+                Some(property_schema) => errors.extend(schema_errors(
+                    property,
+                    property_schema,
+                    &format!("{}.{}", path, key),
+                )),
+                None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                    errors.push(format!("{}.{} is not allowed", path, key))
+                }
+                None => {}
+            }
+        }
+    }
+    if let (Some(items), Some(item_schema)) = (value.as_array(), schema.get("items")) {
+        for (index, item) in items.iter().enumerate() {
+            errors.extend(schema_errors(
+                item,
+                item_schema,
+                &format!("{}[{}]", path, index),
+            ));
+        }
+    }
+    errors
+}
+
+/// `-` for the lines of the snapshot only, `+` for the ones of the output only
+fn line_diff(old: &str, new: &str) -> String {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    // longest common subsequence lengths of the suffixes
+    let mut lengths = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if old[i] == new[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            diff.push_str(&format!("  {}\n", old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lengths[i + 1][j] >= lengths[i][j + 1]) {
+            diff.push_str(&format!("- {}\n", old[i]));
+            i += 1;
+        } else {
+            diff.push_str(&format!("+ {}\n", new[j]));
+            j += 1;
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn check_output(assertion: Assertion, output: &str) -> Result<(), String> {
+        let cache = ResponseCache::new(&Default::default(), true, false);
+        check(&assertion, output, &Prompt::empty(), None, &cache)
+    }
+
+    #[test]
+    fn test_eval_file_parsing() {
+        let eval_file: EvalFile = toml::from_str(
+            r#"
+            grader = "openai:gpt-4o"
+
+            [[case]]
+            name = "short commit message"
+            template = "commit-msg"
+            input_file = "fixtures/fix.diff"
+            vars = { language = "english" }
+            snapshot = true
+            assert = [{ max_length = 72 }, { regex = "^[A-Z]" }, { rubric = "in the imperative mood" }]
+
+            [[case]]
+            name = "default template"
+            input = "say hi"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(eval_file.grader.as_deref(), Some("openai:gpt-4o"));
+        assert_eq!(eval_file.cases[0].template, "commit-msg");
+        assert_eq!(
+            eval_file.cases[0].assertions,
+            vec![
+                Assertion::MaxLength(72),
+                Assertion::Regex("^[A-Z]".to_string()),
+                Assertion::Rubric("in the imperative mood".to_string()),
+            ]
+        );
+        assert_eq!(eval_file.cases[1].template, "default");
+        assert!(!eval_file.cases[1].snapshot);
+    }
+
+    #[test]
+    fn test_assertions() {
+        let output = "Fix the parsing of empty files";
+
+        assert!(check_output(Assertion::Contains("parsing".to_string()), output).is_ok());
+        assert!(check_output(Assertion::NotContains("parsing".to_string()), output).is_err());
+        assert!(check_output(Assertion::Regex("^Fix ".to_string()), output).is_ok());
+        assert_eq!(
+            check_output(Assertion::MaxLength(10), output),
+            Err("expected at most 10 chars, got 30".to_string())
+        );
+        assert!(check_output(Assertion::JsonSchema(json!({})), output)
+            .unwrap_err()
+            .starts_with("expected json output"));
+    }
+
+    #[test]
+    fn test_schema_errors() {
+        let schema = json!({
+            "type": "object",
+            "required": ["title", "labels"],
+            "additionalProperties": false,
+            "properties": {
+                "title": {"type": "string"},
+                "labels": {"type": "array", "items": {"enum": ["bug", "feature"]}}
+            }
+        });
+
+        assert!(schema_errors(&json!({"title": "a", "labels": ["bug"]}), &schema, "$").is_empty());
+        assert_eq!(
+            schema_errors(
+                &json!({"title": 1, "labels": ["chore"], "extra": true}),
+                &schema,
+                "$"
+            ),
+            vec![
+                "$.extra is not allowed",
+                "$.labels[0] is not one of [\"bug\",\"feature\"]",
+                "$.title is not of type string",
+            ]
+        );
+        assert_eq!(
+            schema_errors(&json!([]), &schema, "$"),
+            vec!["$ is not of type object"]
+        );
+    }
+
+    #[test]
+    fn test_snapshot_file_name() {
+        assert_eq!(
+            snapshot_file_name("short commit message"),
+            "short commit message.txt"
+        );
+        assert_eq!(snapshot_file_name("../fix: a/b"), ".._fix_ a_b.txt");
+    }
+
+    #[test]
+    fn test_check_snapshot_names() {
+        let cases = |names: &[&str]| -> Vec<EvalCase> {
+            names
+                .iter()
+                .map(|name| toml::from_str(&format!("name = {:?}\nsnapshot = true", name)).unwrap())
+                .collect()
+        };
+
+        assert!(check_snapshot_names(&cases(&["a/b", "a b"])).is_ok());
+        assert_eq!(
+            check_snapshot_names(&cases(&["a/b", "a_b"])),
+            Err(
+                "the cases `a/b` and `a_b` would share the snapshot file `a_b.txt`, \
+                rename one of them"
+                    .to_string()
+            )
+        );
+        assert!(check_snapshot_names(&cases(&["Fix", "fix"])).is_err());
+
+        let mut without_snapshot = cases(&["a/b", "a_b"]);
+        without_snapshot[1].snapshot = false;
+        assert!(check_snapshot_names(&without_snapshot).is_ok());
+    }
+
+    #[test]
+    fn test_check_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot_path = dir.path().join("snapshots/case.txt");
+
+        assert_eq!(check_snapshot(&snapshot_path, "a\nb", false), Ok(true));
+        assert_eq!(check_snapshot(&snapshot_path, "a\nb", false), Ok(false));
+        assert_eq!(
+            check_snapshot(&snapshot_path, "a\nc", false),
+            Err("the output differs from the snapshot:\n  a\n- b\n+ c\n".to_string())
+        );
+        assert_eq!(check_snapshot(&snapshot_path, "a\nc", true), Ok(true));
+
+        let blocked = dir.path().join("file");
+        fs::write(&blocked, "").unwrap();
+        assert!(check_snapshot(&blocked.join("case.txt"), "a", false)
+            .unwrap_err()
+            .starts_with("could not create"));
+    }
+
+    #[test]
+    fn test_line_diff() {
+        assert_eq!(
+            line_diff(
+                "Fix parsing\n\nEmpty files crashed.",
+                "Fix parsing\n\nEmpty files panicked."
+            ),
+            "  Fix parsing\n  \n- Empty files crashed.\n+ Empty files panicked.\n"
+        );
+    }
+}
@@ -0,0 +1,365 @@
+use serde::Serialize;
+
+use crate::config::{
+    api::Api,
+    prompt::{Message, Prompt},
+};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    /// markdown, to paste in a PR or a wiki page
+    Md,
+    /// the messages with their metadata
+    Json,
+    /// a standalone page
+    Html,
+    /// one `{"messages": [...]}` line, the format of the fine-tuning datasets
+    Jsonl,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportedConversation<'a> {
+    api: &'a Api,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    messages: Vec<ExportedMessage<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportedMessage<'a> {
+    role: &'a str,
+    content: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api: Option<&'a Api>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    cached: bool,
+}
+
+/// for the fine-tuning datasets, only what's sent to the model
+#[derive(Debug, Serialize)]
+struct TrainingExample<'a> {
+    messages: Vec<TrainingMessage<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct TrainingMessage<'a> {
+    role: &'a str,
+    content: &'a str,
+}
+
+pub fn export(prompt: &Prompt, format: ExportFormat) -> String {
+    match format {
+        ExportFormat::Md => to_markdown(prompt),
+        ExportFormat::Json => {
+            let mut json = serde_json::to_string_pretty(&exported(prompt))
+                .expect("Failed to serialize the conversation");
+            json.push('\n');
+            json
+        }
+        ExportFormat::Html => to_html(prompt),
+        ExportFormat::Jsonl => {
+            let example = TrainingExample {
+                messages: prompt
+                    .messages
+                    .iter()
+                    .map(|message| TrainingMessage {
+                        role: &message.role,
+                        content: &message.content,
+                    })
+                    .collect(),
+            };
+            let mut line =
+                serde_json::to_string(&example).expect("Failed to serialize the conversation");
+            line.push('\n');
+            line
+        }
+    }
+}
+
+fn exported(prompt: &Prompt) -> ExportedConversation<'_> {
+    ExportedConversation {
+        api: &prompt.api,
+        model: prompt.model.as_deref(),
+        temperature: prompt.temperature,
+        messages: prompt
+            .messages
+            .iter()
+            .map(|message| {
+                let metadata = message.metadata.as_ref();
+                ExportedMessage {
+                    role: &message.role,
+                    content: &message.content,
+                    api: metadata.map(|metadata| &metadata.answered_by.api),
+                    model: metadata.and_then(|metadata| metadata.answered_by.model.as_deref()),
+                    created_at: metadata
+                        .and_then(|metadata| metadata.created_at)
+                        .map(format_timestamp),
+                    cached: metadata.is_some_and(|metadata| metadata.cached),
+                }
+            })
+            .collect(),
+    }
+}
+
+/// settings of the conversation, as `name: value` pairs
+fn header(prompt: &Prompt) -> Vec<(&'static str, String)> {
+    let mut header = vec![("api", prompt.api.to_string())];
+    if let Some(model) = &prompt.model {
+        header.push(("model", model.clone()));
+    }
+    if let Some(temperature) = prompt.temperature {
+        header.push(("temperature", temperature.to_string()));
+    }
+    header
+}
+
+/// `openai gpt-4o, 2024-05-01 13:37:00 UTC` for the answers
+fn message_details(message: &Message) -> Option<String> {
+    let metadata = message.metadata.as_ref()?;
+    let mut details = metadata.answered_by.api.to_string();
+    if let Some(model) = &metadata.answered_by.model {
+        details.push_str(&format!(" {}", model));
+    }
+    if let Some(created_at) = metadata.created_at {
+        details.push_str(&format!(
+            ", {}",
+            format_timestamp(created_at)
+                .replace('T', " ")
+                .replace('Z', " UTC")
+        ));
+    }
+    if metadata.cached {
+        details.push_str(", from the cache");
+    }
+    Some(details)
+}
+
+fn title(role: &str) -> String {
+    let mut chars = role.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+fn to_markdown(prompt: &Prompt) -> String {
+    let mut markdown = String::from("# Conversation\n\n");
+    for (name, value) in header(prompt) {
+        markdown.push_str(&format!("- {}: {}\n", name, value));
+    }
+    for message in &prompt.messages {
+        markdown.push_str(&format!("\n## {}\n\n", title(&message.role)));
+        if let Some(details) = message_details(message) {
+            markdown.push_str(&format!("_{}_\n\n", details));
+        }
+        markdown.push_str(message.content.trim_end());
+        markdown.push('\n');
+    }
+    markdown
+}
+
+fn to_html(prompt: &Prompt) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+        <title>Conversation</title>\n<style>\n\
+        body { font-family: sans-serif; max-width: 50rem; margin: auto; padding: 1rem; }\n\
+        section { border-left: 4px solid #ccc; padding-left: 1rem; margin: 1.5rem 0; }\n\
+        section.assistant { border-color: #4a90d9; }\n\
+        section.system { border-color: #999; color: #555; }\n\
+        .details { color: #777; font-size: 0.9em; }\n\
+        pre { background: #f5f5f5; padding: 0.5rem; overflow-x: auto; }\n\
+        </style>\n</head>\n<body>\n<h1>Conversation</h1>\n<ul>\n",
+    );
+    for (name, value) in header(prompt) {
+        html.push_str(&format!("<li>{}: {}</li>\n", name, escape_html(&value)));
+    }
+    html.push_str("</ul>\n");
+    for message in &prompt.messages {
+        html.push_str(&format!(
+            "<section class=\"{}\">\n<h2>{}</h2>\n",
+            escape_html(&message.role),
+            escape_html(&title(&message.role))
+        ));
+        if let Some(details) = message_details(message) {
+            html.push_str(&format!(
+                "<p class=\"details\">{}</p>\n",
+                escape_html(&details)
+            ));
+        }
+        html.push_str(&content_to_html(&message.content));
+        html.push_str("</section>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// fenced code blocks become `<pre>`, the rest paragraphs keeping the line breaks
+fn content_to_html(content: &str) -> String {
+    let mut html = String::new();
+    let mut paragraph: Vec<&str> = Vec::new();
+    let mut code: Option<Vec<&str>> = None;
+
+    let flush_paragraph = |paragraph: &mut Vec<&str>, html: &mut String| {
+        if !paragraph.is_empty() {
+            let lines: Vec<String> = paragraph.iter().map(|line| escape_html(line)).collect();
+            html.push_str(&format!("<p>{}</p>\n", lines.join("<br>\n")));
+            paragraph.clear();
+        }
+    };
+
+    for line in content.lines() {
+        let is_fence = line.trim_start().starts_with("```");
+        match code.as_mut() {
+            Some(code_lines) if is_fence => {
+                html.push_str(&format!(
+                    "<pre><code>{}</code></pre>\n",
+                    escape_html(&code_lines.join("\n"))
+                ));
+                code = None;
+            }
+            Some(code_lines) => code_lines.push(line),
+            None if is_fence => {
+                flush_paragraph(&mut paragraph, &mut html);
+                code = Some(Vec::new());
+            }
+            None if line.trim().is_empty() => flush_paragraph(&mut paragraph, &mut html),
+            None => paragraph.push(line),
+        }
+    }
+    // an unclosed block still shows as code
+    if let Some(code_lines) = code {
+        html.push_str(&format!(
+            "<pre><code>{}</code></pre>\n",
+            escape_html(&code_lines.join("\n"))
+        ));
+    }
+    flush_paragraph(&mut paragraph, &mut html);
+    html
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// `2024-05-01T13:37:00Z`, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn format_timestamp(unix_seconds: u64) -> String {
+    let days = (unix_seconds / 86_400) as i64 + 719_468;
+    let seconds_of_day = unix_seconds % 86_400;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::prompt::{Backend, MessageMetadata};
+
+    fn conversation() -> Prompt {
+        Prompt {
+            api: Api::Openai,
+            model: Some("gpt-4o".to_string()),
+            temperature: Some(0.5),
+            messages: vec![
+                Message::system("be brief"),
+                Message::user("how do I print <T> in rust?"),
+                Message {
+                    metadata: Some(MessageMetadata {
+                        answered_by: Backend {
+                            api: Api::Openai,
+                            model: Some("gpt-4o".to_string()),
+                        },
+                        created_at: Some(1_714_570_620),
+                        cached: false,
+                        usage: None,
+                    }),
+                    ..Message::assistant("like this:\n```rust\nprintln!(\"{}\", x);\n```")
+                },
+            ],
+            ..Prompt::empty()
+        }
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_timestamp(1_714_570_620), "2024-05-01T13:37:00Z");
+    }
+
+    #[test]
+    fn test_export_markdown() {
+        assert_eq!(
+            export(&conversation(), ExportFormat::Md),
+            "# Conversation\n\n\
+            - api: openai\n\
+            - model: gpt-4o\n\
+            - temperature: 0.5\n\
+            \n## System\n\nbe brief\n\
+            \n## User\n\nhow do I print <T> in rust?\n\
+            \n## Assistant\n\n_openai gpt-4o, 2024-05-01 13:37:00 UTC_\n\n\
+            like this:\n```rust\nprintln!(\"{}\", x);\n```\n"
+        );
+    }
+
+    #[test]
+    fn test_export_html_escapes_and_renders_code_blocks() {
+        let html = export(&conversation(), ExportFormat::Html);
+
+        assert!(html.contains("<p>how do I print &lt;T&gt; in rust?</p>"));
+        assert!(html.contains("<pre><code>println!(&quot;{}&quot;, x);</code></pre>"));
+        assert!(html.contains("<p class=\"details\">openai gpt-4o, 2024-05-01 13:37:00 UTC</p>"));
+    }
+
+    #[test]
+    fn test_export_json_and_jsonl() {
+        let json: serde_json::Value =
+            serde_json::from_str(&export(&conversation(), ExportFormat::Json)).unwrap();
+        let jsonl = export(&conversation(), ExportFormat::Jsonl);
+        let mut cached_conversation = conversation();
+        if let Some(metadata) = cached_conversation.messages[2].metadata.as_mut() {
+            metadata.cached = true;
+        }
+        let cached_json: serde_json::Value =
+            serde_json::from_str(&export(&cached_conversation, ExportFormat::Json)).unwrap();
+
+        assert_eq!(json["messages"][2]["created_at"], "2024-05-01T13:37:00Z");
+        assert_eq!(json["messages"][2]["model"], "gpt-4o");
+        assert!(json["messages"][2].get("cached").is_none());
+        assert_eq!(cached_json["messages"][2]["cached"], true);
+        assert!(json["messages"][0].get("api").is_none());
+        assert_eq!(jsonl.lines().count(), 1);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&jsonl).unwrap()["messages"][0],
+            serde_json::json!({"role": "system", "content": "be brief"})
+        );
+    }
+}
@@ -0,0 +1,288 @@
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+use crate::config::prompt::Message;
+
+pub const ROLES: [&str; 3] = ["system", "user", "assistant"];
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ImportFormat {
+    /// a transcript with a `## User`, `## Assistant` or `## System` header before each message
+    Md,
+    /// an array of `{"role": ..., "content": ...}` messages or an object with a `messages` key
+    Json,
+    /// one `{"input": ..., "output": ...}` example or `{"messages": [...]}` conversation per line
+    Jsonl,
+}
+
+impl ImportFormat {
+    /// from the extension of the file, markdown otherwise
+    pub fn from_path(path: &Path) -> ImportFormat {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => ImportFormat::Json,
+            Some("jsonl") => ImportFormat::Jsonl,
+            _ => ImportFormat::Md,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum JsonMessages {
+    Array(Vec<JsonMessage>),
+    Object { messages: Vec<JsonMessage> },
+}
+
+/// a message in the openai format
+#[derive(Debug, Deserialize)]
+pub struct JsonMessage {
+    role: String,
+    content: JsonContent,
+}
+
+/// a plain string or, as in the openai format, a list of parts of which only the text is kept
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum JsonContent {
+    Text(String),
+    Parts(Vec<JsonContentPart>),
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonContentPart {
+    #[serde(default)]
+    text: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum JsonlLine {
+    Example { input: String, output: String },
+    Conversation { messages: Vec<JsonMessage> },
+}
+
+impl From<JsonMessage> for Message {
+    fn from(message: JsonMessage) -> Message {
+        let content = match message.content {
+            JsonContent::Text(text) => text,
+            JsonContent::Parts(parts) => parts
+                .into_iter()
+                .filter_map(|part| part.text)
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+        Message {
+            role: message.role,
+            content,
+            metadata: None,
+        }
+    }
+}
+
+pub fn load_messages(path: &Path, format: Option<ImportFormat>) -> Result<Vec<Message>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|error| format!("Could not read file {:?}, {}", path, error))?;
+    let format = format.unwrap_or_else(|| ImportFormat::from_path(path));
+    parse_messages(&content, format).map_err(|error| format!("{}: {}", path.display(), error))
+}
+
+pub fn parse_messages(content: &str, format: ImportFormat) -> Result<Vec<Message>, String> {
+    let messages = match format {
+        ImportFormat::Md => from_markdown(content),
+        ImportFormat::Json => {
+            let messages = match serde_json::from_str(content)
+                .map_err(|e| format!("expected an array of messages: {}", e))?
+            {
+                JsonMessages::Array(messages) | JsonMessages::Object { messages } => messages,
+            };
+            messages.into_iter().map(Message::from).collect()
+        }
+        ImportFormat::Jsonl => from_jsonl(content)?,
+    };
+    if messages.is_empty() {
+        return Err("no message found".to_string());
+    }
+    if let Some(message) = messages
+        .iter()
+        .find(|message| !ROLES.contains(&message.role.as_str()))
+    {
+        return Err(format!(
+            "unknown role `{}`, expected one of {:?}",
+            message.role, ROLES
+        ));
+    }
+    Ok(messages)
+}
+
+fn from_jsonl(content: &str) -> Result<Vec<Message>, String> {
+    let mut messages = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(line).map_err(|_| {
+            format!(
+                "line {}: expected an `input` and an `output` or a list of `messages`",
+                index + 1
+            )
+        })? {
+            JsonlLine::Example { input, output } => {
+                messages.push(Message::user(&input));
+                messages.push(Message::assistant(&output));
+            }
+            JsonlLine::Conversation {
+                messages: conversation,
+            } => messages.extend(conversation.into_iter().map(Message::from)),
+        }
+    }
+    Ok(messages)
+}
+
+/// the role of a `# User`, `## assistant`, ... header line
+fn role_header(line: &str) -> Option<&'static str> {
+    let title = line.strip_prefix('#')?.trim_start_matches('#').trim();
+    ROLES
+        .into_iter()
+        .find(|role| title.eq_ignore_ascii_case(role))
+}
+
+/// the text before the first role header, such as the header of `sc export`, is left out
+fn from_markdown(content: &str) -> Vec<Message> {
+    let mut messages: Vec<Message> = Vec::new();
+    let mut in_code_block = false;
+
+    for line in content.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code_block = !in_code_block;
+        }
+        match role_header(line).filter(|_| !in_code_block) {
+            Some(role) => messages.push(Message {
+                role: role.to_string(),
+                content: String::new(),
+                metadata: None,
+            }),
+            None => {
+                if let Some(message) = messages.last_mut() {
+                    message.content.push_str(line);
+                    message.content.push('\n');
+                }
+            }
+        }
+    }
+
+    for message in messages.iter_mut() {
+        let mut content = message.content.trim();
+        // the `_openai gpt-4o, 2024-05-01 13:37:00 UTC_` line `sc export` adds to the answers
+        if message.role == "assistant" {
+            if let Some((first_line, rest)) = content.split_once('\n') {
+                if first_line.len() > 2 && first_line.starts_with('_') && first_line.ends_with('_')
+                {
+                    content = rest.trim_start();
+                }
+            }
+        }
+        message.content = content.to_string();
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::prompt::Prompt;
+    use crate::export::{export, ExportFormat};
+
+    #[test]
+    fn test_import_markdown_transcript() {
+        let messages = parse_messages(
+            "Some notes\n\n# System\nbe brief\n\n## user\nwhat is this?\n```md\n## User\n```\n\n### Assistant\na code block\n",
+            ImportFormat::Md,
+        )
+        .unwrap();
+
+        assert_eq!(
+            messages,
+            vec![
+                Message::system("be brief"),
+                Message::user("what is this?\n```md\n## User\n```"),
+                Message::assistant("a code block"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_import_markdown_export() {
+        let prompt = Prompt {
+            messages: vec![
+                Message::system("be brief"),
+                Message::user("hi"),
+                Message::assistant("hello\n\nthere"),
+            ],
+            ..Prompt::empty()
+        };
+
+        assert_eq!(
+            parse_messages(&export(&prompt, ExportFormat::Md), ImportFormat::Md).unwrap(),
+            prompt.messages
+        );
+    }
+
+    #[test]
+    fn test_import_openai_json() {
+        let array = r#"[
+            {"role": "system", "content": "be brief"},
+            {"role": "user", "content": [{"type": "text", "text": "hi"}, {"type": "image_url", "image_url": {"url": "x"}}]}
+        ]"#;
+        let object = r#"{"api": "openai", "messages": [{"role": "assistant", "content": "hello", "model": "gpt-4o"}]}"#;
+
+        assert_eq!(
+            parse_messages(array, ImportFormat::Json).unwrap(),
+            vec![Message::system("be brief"), Message::user("hi")]
+        );
+        assert_eq!(
+            parse_messages(object, ImportFormat::Json).unwrap(),
+            vec![Message::assistant("hello")]
+        );
+        assert!(
+            parse_messages(r#"[{"role": "tool", "content": "{}"}]"#, ImportFormat::Json)
+                .unwrap_err()
+                .contains("unknown role `tool`")
+        );
+    }
+
+    #[test]
+    fn test_import_jsonl_examples() {
+        let examples = "{\"input\": \"1 + 1\", \"output\": \"2\"}\n\n{\"input\": \"2 * 3\", \"output\": \"6\"}\n";
+
+        assert_eq!(
+            parse_messages(examples, ImportFormat::Jsonl).unwrap(),
+            vec![
+                Message::user("1 + 1"),
+                Message::assistant("2"),
+                Message::user("2 * 3"),
+                Message::assistant("6"),
+            ]
+        );
+        assert_eq!(
+            parse_messages("{\"input\": \"1 + 1\"}", ImportFormat::Jsonl).unwrap_err(),
+            "line 1: expected an `input` and an `output` or a list of `messages`"
+        );
+    }
+
+    #[test]
+    fn test_import_format_from_path() {
+        assert_eq!(
+            ImportFormat::from_path(Path::new("chat.json")),
+            ImportFormat::Json
+        );
+        assert_eq!(
+            ImportFormat::from_path(Path::new("examples.jsonl")),
+            ImportFormat::Jsonl
+        );
+        assert_eq!(
+            ImportFormat::from_path(Path::new("transcript.md")),
+            ImportFormat::Md
+        );
+    }
+}
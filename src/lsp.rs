@@ -0,0 +1,673 @@
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc::{self, Sender};
+
+use crate::config::api::get_api_configs;
+use crate::config::ensure_config_files;
+use crate::config::prompt::{get_prompts, Message};
+use crate::config::settings::get_settings;
+use crate::prompt_customization::customize_prompt;
+use crate::text::{cache::ResponseCache, insert_input, request_answer};
+use crate::utils::{unfence, IS_NONINTERACTIVE_ENV_VAR};
+use crate::PromptParams;
+
+/// the command the code actions run, the answer being applied with `workspace/applyEdit`
+const RUN_COMMAND: &str = "smartcat.run";
+
+// json-rpc error codes
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+struct Position {
+    line: u32,
+    /// in utf-16 code units, the lsp default
+    character: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+struct Range {
+    start: Position,
+    end: Position,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum EditMode {
+    /// the answer replaces the selection
+    Replace,
+    /// the answer goes below the selection
+    Insert,
+}
+
+/// the argument of the command of a code action
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+struct RunArgs {
+    uri: String,
+    range: Range,
+    template: String,
+    mode: EditMode,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    diagnostics: Vec<String>,
+    /// of the document when the action was offered, the range is stale once it changed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<i64>,
+}
+
+struct Document {
+    text: String,
+    version: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Diagnostic {
+    range: Range,
+    #[serde(default)]
+    severity: Option<u8>,
+    message: String,
+}
+
+/// error answered to a request
+#[derive(Debug, PartialEq)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> RpcError {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+struct Server {
+    /// the open documents, by uri
+    documents: HashMap<String, Document>,
+    /// pass the diagnostics of the selection along with it
+    with_diagnostics: bool,
+    /// id of the next request sent to the editor
+    next_id: u64,
+    /// the messages the templates running in the background send to the editor
+    outgoing: Sender<Value>,
+}
+
+/// Speak the language server protocol over stdin and stdout until the editor exits.
+pub fn run(with_diagnostics: bool) -> io::Result<()> {
+    // stdin and stdout are the json-rpc channel, nothing can be asked nor printed there
+    std::env::set_var(IS_NONINTERACTIVE_ENV_VAR, "1");
+    ensure_config_files()?;
+
+    let (outgoing, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut writer = io::stdout().lock();
+        for message in receiver {
+            if let Err(e) = write_message(&mut writer, &message) {
+                debug!("failed to write to the editor: {}", e);
+            }
+        }
+    });
+    let mut server = Server {
+        documents: HashMap::new(),
+        with_diagnostics,
+        next_id: 0,
+        outgoing: outgoing.clone(),
+    };
+    let mut reader = io::stdin().lock();
+    while let Some(message) = read_message(&mut reader)? {
+        if message["method"] == "exit" {
+            break;
+        }
+        let id = message.get("id").cloned();
+        // a broken config file panics, the request fails but the server keeps going
+        let answers =
+            catch_unwind(AssertUnwindSafe(|| server.handle(message))).unwrap_or_else(|_| {
+                answer(id, Err(RpcError::new(INTERNAL_ERROR, "smartcat panicked")))
+            });
+        for message in answers {
+            let _ = outgoing.send(message);
+        }
+    }
+    Ok(())
+}
+
+impl Server {
+    /// the messages to send back, answers to the requests and requests to the editor
+    fn handle(&mut self, message: Value) -> Vec<Value> {
+        let Some(method) = message["method"].as_str() else {
+            // answer to one of our `workspace/applyEdit`
+            debug!("editor answered {}", message);
+            return Vec::new();
+        };
+        let params = message["params"].clone();
+        let id = message.get("id").cloned();
+        debug!("{} {}", method, params);
+        if method == "workspace/executeCommand" {
+            return self.execute_command(id, &params);
+        }
+
+        let result = match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    // the whole text is sent on every change
+                    "textDocumentSync": 1,
+                    "codeActionProvider": true,
+                    "executeCommandProvider": {"commands": [RUN_COMMAND]},
+                },
+                "serverInfo": {"name": "smartcat", "version": env!("CARGO_PKG_VERSION")},
+            })),
+            "shutdown" => Ok(Value::Null),
+            "textDocument/didOpen" => {
+                if let (Some(uri), Some(text)) = (
+                    params["textDocument"]["uri"].as_str(),
+                    params["textDocument"]["text"].as_str(),
+                ) {
+                    self.open(uri, text, params["textDocument"]["version"].as_i64());
+                }
+                Ok(Value::Null)
+            }
+            "textDocument/didChange" => {
+                let text = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str());
+                if let (Some(uri), Some(text)) = (params["textDocument"]["uri"].as_str(), text) {
+                    self.open(uri, text, params["textDocument"]["version"].as_i64());
+                }
+                Ok(Value::Null)
+            }
+            "textDocument/didClose" => {
+                if let Some(uri) = params["textDocument"]["uri"].as_str() {
+                    self.documents.remove(uri);
+                }
+                Ok(Value::Null)
+            }
+            "textDocument/codeAction" => {
+                let mut templates: Vec<String> = get_prompts().into_keys().collect();
+                templates.sort();
+                self.code_actions(&params, &templates)
+            }
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, method)),
+        };
+
+        answer(id, result)
+    }
+
+    fn open(&mut self, uri: &str, text: &str, version: Option<i64>) {
+        let document = Document {
+            text: text.to_string(),
+            version,
+        };
+        self.documents.insert(uri.to_string(), document);
+    }
+
+    /// Check the command and run its template in the background, so that the editor isn't
+    /// blocked meanwhile. The answer to the command comes with the request to apply the edit.
+    fn execute_command(&mut self, id: Option<Value>, params: &Value) -> Vec<Value> {
+        let args = match params["command"].as_str() {
+            Some(RUN_COMMAND) => serde_json::from_value::<RunArgs>(params["arguments"][0].clone())
+                .map_err(|e| RpcError::new(INVALID_PARAMS, e)),
+            _ => Err(RpcError::new(INVALID_PARAMS, "unknown command")),
+        };
+        let (args, selection, version) = match args.and_then(|args| self.selection(args)) {
+            Ok(checked) => checked,
+            Err(error) => return answer(id, Err(error)),
+        };
+        self.next_id += 1;
+        let edit_id = format!("smartcat-{}", self.next_id);
+        let outgoing = self.outgoing.clone();
+        std::thread::spawn(move || {
+            let edit = catch_unwind(|| run_template(&args, &selection, version))
+                .unwrap_or_else(|_| Err(RpcError::new(INTERNAL_ERROR, "smartcat panicked")));
+            let messages = match edit {
+                Ok(edit) => {
+                    let mut messages = answer(id, Ok(Value::Null));
+                    messages.push(json!({
+                        "jsonrpc": "2.0",
+                        "id": edit_id,
+                        "method": "workspace/applyEdit",
+                        "params": {"label": format!("sc {}", args.template), "edit": edit},
+                    }));
+                    messages
+                }
+                Err(error) => answer(id, Err(error)),
+            };
+            for message in messages {
+                let _ = outgoing.send(message);
+            }
+        });
+        Vec::new()
+    }
+
+    /// the selected text and the version of the document, if the range is still valid
+    fn selection(&self, args: RunArgs) -> Result<(RunArgs, String, Option<i64>), RpcError> {
+        let document = self
+            .documents
+            .get(&args.uri)
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("{} isn't open", args.uri)))?;
+        if args.version.is_some() && args.version != document.version {
+            return Err(RpcError::new(
+                INVALID_PARAMS,
+                "the document changed since the action was offered",
+            ));
+        }
+        let start = offset(&document.text, args.range.start);
+        let end = offset(&document.text, args.range.end);
+        if start > end {
+            return Err(RpcError::new(
+                INVALID_PARAMS,
+                "the range ends before it starts",
+            ));
+        }
+        let selection = document.text[start..end].to_string();
+        Ok((args, selection, document.version))
+    }
+
+    /// two actions per template, replacing the selection or inserting below it
+    fn code_actions(&self, params: &Value, templates: &[String]) -> Result<Value, RpcError> {
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, "missing uri"))?;
+        let version = self
+            .documents
+            .get(uri)
+            .and_then(|document| document.version);
+        let range: Range = serde_json::from_value(params["range"].clone())
+            .map_err(|e| RpcError::new(INVALID_PARAMS, e))?;
+        let range = line_if_empty(range);
+        let diagnostics = if self.with_diagnostics {
+            serde_json::from_value::<Vec<Diagnostic>>(params["context"]["diagnostics"].clone())
+                .unwrap_or_default()
+                .iter()
+                .map(format_diagnostic)
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let mut actions = Vec::new();
+        for template in templates {
+            for (mode, title) in [
+                (EditMode::Replace, "replace the selection"),
+                (EditMode::Insert, "insert below"),
+            ] {
+                let args = RunArgs {
+                    uri: uri.to_string(),
+                    range,
+                    template: template.clone(),
+                    mode,
+                    diagnostics: diagnostics.clone(),
+                    version,
+                };
+                actions.push(json!({
+                    "title": format!("sc {}: {}", template, title),
+                    "kind": "refactor",
+                    "command": {
+                        "title": format!("sc {}", template),
+                        "command": RUN_COMMAND,
+                        "arguments": [args],
+                    },
+                }));
+            }
+        }
+        Ok(Value::Array(actions))
+    }
+}
+
+/// Send the selection to the template, returns the workspace edit applying the answer. The edit
+/// is tied to the version of the document, the editor refuses it once the document changed.
+fn run_template(args: &RunArgs, selection: &str, version: Option<i64>) -> Result<Value, RpcError> {
+    let template = get_prompts().remove(&args.template).ok_or_else(|| {
+        RpcError::new(
+            INVALID_PARAMS,
+            format!("no `{}` prompt template", args.template),
+        )
+    })?;
+
+    let mut prompt = customize_prompt(template, &PromptParams::default(), None, selection);
+    prompt.resolve_aliases(&get_settings().aliases, None);
+    if !get_api_configs().contains_key(&prompt.api.to_string()) {
+        return Err(RpcError::new(
+            INVALID_PARAMS,
+            format!("`{}` isn't configured in the api config file", prompt.api),
+        ));
+    }
+    if !args.diagnostics.is_empty() {
+        // right before the input, as the context files are
+        let input_index = prompt.messages.len() - 1;
+        prompt.messages.insert(
+            input_index,
+            Message::system(&format!(
+                "diagnostics of the editor for the selected code:\n{}",
+                args.diagnostics.join("\n")
+            )),
+        );
+    }
+    let redactions = insert_input(&mut prompt, selection, false);
+    let cache = ResponseCache::new(&get_settings().cache, false, false);
+    let message = request_answer(&prompt, &cache).map_err(|e| RpcError::new(INTERNAL_ERROR, e))?;
+    let answer = if get_settings().redaction.restore {
+        redactions.restore(&message.content)
+    } else {
+        message.content
+    };
+
+    Ok(json!({"documentChanges": [{
+        "textDocument": {"uri": args.uri, "version": version},
+        "edits": [text_edit(args.range, args.mode, &answer)],
+    }]}))
+}
+
+/// the answer to a request, nothing for a notification
+fn answer(id: Option<Value>, result: Result<Value, RpcError>) -> Vec<Value> {
+    match (id, result) {
+        (Some(id), Ok(result)) => vec![json!({"jsonrpc": "2.0", "id": id, "result": result})],
+        (Some(id), Err(error)) => vec![json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {"code": error.code, "message": error.message},
+        })],
+        (None, _) => Vec::new(),
+    }
+}
+
+/// the whole line of the cursor when nothing is selected
+fn line_if_empty(range: Range) -> Range {
+    if range.start != range.end {
+        return range;
+    }
+    Range {
+        start: Position {
+            line: range.start.line,
+            character: 0,
+        },
+        end: Position {
+            line: range.start.line + 1,
+            character: 0,
+        },
+    }
+}
+
+/// `12:5 error: cannot find value `x` in this scope`, 1-based as editors show them
+fn format_diagnostic(diagnostic: &Diagnostic) -> String {
+    let severity = match diagnostic.severity {
+        Some(1) => "error",
+        Some(2) => "warning",
+        Some(3) => "info",
+        _ => "hint",
+    };
+    format!(
+        "{}:{} {}: {}",
+        diagnostic.range.start.line + 1,
+        diagnostic.range.start.character + 1,
+        severity,
+        diagnostic.message
+    )
+}
+
+fn text_edit(range: Range, mode: EditMode, answer: &str) -> Value {
+    let answer = unfence(answer);
+    match mode {
+        EditMode::Replace => {
+            // keep the line break of a selection of whole lines, and only that one
+            let new_text = if range.end.character == 0 {
+                format!("{}\n", answer.trim_end_matches('\n'))
+            } else {
+                answer.trim_end_matches('\n').to_string()
+            };
+            json!({"range": range, "newText": new_text})
+        }
+        EditMode::Insert => {
+            let new_text = if range.end.character == 0 {
+                format!("{}\n", answer.trim_end())
+            } else {
+                format!("\n{}", answer.trim_end())
+            };
+            json!({"range": {"start": range.end, "end": range.end}, "newText": new_text})
+        }
+    }
+}
+
+/// byte offset of the position in the text, clamped to the end of its line
+fn offset(text: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match text[line_start..].find('\n') {
+            Some(index) => line_start += index + 1,
+            None => return text.len(),
+        }
+    }
+    let line = &text[line_start..];
+    let line = &line[..line.find('\n').unwrap_or(line.len())];
+    let mut units = 0;
+    for (index, c) in line.char_indices() {
+        if units >= position.character {
+            return line_start + index;
+        }
+        units += c.len_utf16() as u32;
+    }
+    line_start + line.len()
+}
+
+/// a message framed by its `Content-Length` header, none once the input is closed
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            if content_length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let mut body = vec![0; content_length.unwrap_or_default()];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = serde_json::to_string(message)?;
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(line: u32, character: u32) -> Position {
+        Position { line, character }
+    }
+
+    fn server() -> Server {
+        Server {
+            documents: HashMap::new(),
+            with_diagnostics: true,
+            next_id: 0,
+            outgoing: mpsc::channel().0,
+        }
+    }
+
+    #[test]
+    fn test_message_framing() {
+        let mut framed = Vec::new();
+        write_message(&mut framed, &json!({"jsonrpc": "2.0", "method": "exit"})).unwrap();
+
+        assert_eq!(
+            String::from_utf8(framed.clone()).unwrap(),
+            "Content-Length: 33\r\n\r\n{\"jsonrpc\":\"2.0\",\"method\":\"exit\"}"
+        );
+        let mut reader = framed.as_slice();
+        assert_eq!(
+            read_message(&mut reader).unwrap(),
+            Some(json!({"jsonrpc": "2.0", "method": "exit"}))
+        );
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_offset() {
+        let text = "fn main() {\n    let é = \"😀x\";\n}";
+
+        assert_eq!(offset(text, position(0, 3)), 3);
+        assert_eq!(&text[offset(text, position(1, 8))..], "é = \"😀x\";\n}");
+        // the emoji is two utf-16 code units
+        assert_eq!(&text[offset(text, position(1, 15))..], "x\";\n}");
+        assert_eq!(offset(text, position(0, 99)), 11);
+        assert_eq!(offset(text, position(9, 0)), text.len());
+    }
+
+    #[test]
+    fn test_code_actions() {
+        let params = json!({
+            "textDocument": {"uri": "file:///main.rs"},
+            "range": {"start": {"line": 2, "character": 4}, "end": {"line": 2, "character": 4}},
+            "context": {"diagnostics": [{
+                "range": {"start": {"line": 2, "character": 8}, "end": {"line": 2, "character": 9}},
+                "severity": 1,
+                "message": "cannot find value `x` in this scope",
+            }]},
+        });
+
+        let mut server = server();
+        server.open("file:///main.rs", "fn main() {\n\n    x\n}\n", Some(7));
+        let actions = server
+            .code_actions(&params, &["review".to_string()])
+            .unwrap();
+
+        assert_eq!(actions.as_array().unwrap().len(), 2);
+        assert_eq!(actions[0]["title"], "sc review: replace the selection");
+        assert_eq!(actions[1]["title"], "sc review: insert below");
+        let args: RunArgs =
+            serde_json::from_value(actions[1]["command"]["arguments"][0].clone()).unwrap();
+        assert_eq!(
+            args,
+            RunArgs {
+                uri: "file:///main.rs".to_string(),
+                range: Range {
+                    start: position(2, 0),
+                    end: position(3, 0)
+                },
+                template: "review".to_string(),
+                mode: EditMode::Insert,
+                diagnostics: vec!["3:9 error: cannot find value `x` in this scope".to_string()],
+                version: Some(7),
+            }
+        );
+    }
+
+    #[test]
+    fn test_text_edit() {
+        let lines = Range {
+            start: position(1, 0),
+            end: position(3, 0),
+        };
+        let word = Range {
+            start: position(1, 4),
+            end: position(1, 7),
+        };
+
+        assert_eq!(
+            text_edit(lines, EditMode::Replace, "```rust\nlet x = 1;\n```"),
+            json!({"range": lines, "newText": "let x = 1;\n"})
+        );
+        assert_eq!(
+            text_edit(word, EditMode::Replace, "```\nname\n```"),
+            json!({"range": word, "newText": "name"})
+        );
+        assert_eq!(
+            text_edit(word, EditMode::Insert, "// a comment\n"),
+            json!({"range": {"start": word.end, "end": word.end}, "newText": "\n// a comment"})
+        );
+    }
+
+    #[test]
+    fn test_requests_and_notifications() {
+        let mut server = server();
+
+        let answers =
+            server.handle(json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}}));
+        assert_eq!(
+            answers[0]["result"]["capabilities"]["executeCommandProvider"]["commands"][0],
+            RUN_COMMAND
+        );
+        let answers = server.handle(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {"uri": "file:///a.rs", "text": "fn a() {}", "version": 4}},
+        }));
+        assert!(answers.is_empty());
+        assert_eq!(server.documents["file:///a.rs"].text, "fn a() {}");
+        assert_eq!(server.documents["file:///a.rs"].version, Some(4));
+        let answers =
+            server.handle(json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/hover"}));
+        assert_eq!(answers[0]["error"]["code"], METHOD_NOT_FOUND);
+        let answers = server.handle(json!({
+            "jsonrpc": "2.0",
+            "id": 3,
+            "method": "workspace/executeCommand",
+            "params": {"command": RUN_COMMAND, "arguments": [{
+                "uri": "file:///closed.rs",
+                "range": {"start": {"line": 0, "character": 0}, "end": {"line": 1, "character": 0}},
+                "template": "default",
+                "mode": "replace",
+            }]},
+        }));
+        assert_eq!(
+            answers[0]["error"]["message"],
+            "file:///closed.rs isn't open"
+        );
+    }
+
+    #[test]
+    fn test_selection_checks() {
+        let mut server = server();
+        server.open("file:///a.rs", "fn a() {}\nfn b() {}\n", Some(2));
+        let args = |start, end, version| RunArgs {
+            uri: "file:///a.rs".to_string(),
+            range: Range { start, end },
+            template: "default".to_string(),
+            mode: EditMode::Replace,
+            diagnostics: Vec::new(),
+            version,
+        };
+
+        let (_, selection, version) = server
+            .selection(args(position(1, 0), position(2, 0), Some(2)))
+            .unwrap();
+        assert_eq!((selection.as_str(), version), ("fn b() {}\n", Some(2)));
+        assert_eq!(
+            server
+                .selection(args(position(1, 3), position(0, 0), None))
+                .unwrap_err()
+                .message,
+            "the range ends before it starts"
+        );
+        assert_eq!(
+            server
+                .selection(args(position(0, 0), position(1, 0), Some(1)))
+                .unwrap_err()
+                .message,
+            "the document changed since the action was offered"
+        );
+    }
+}
@@ -1,21 +1,38 @@
 mod config;
+mod context;
+mod embeddings;
+mod eval;
+mod export;
+mod import;
+mod lsp;
 mod prompt_customization;
+mod server;
+mod shell;
 mod text;
 mod utils;
 
 use crate::config::{
-    api::Api,
+    api::{get_api_configs, Api},
     ensure_config_usable,
-    prompt::{conversation_file_path, get_last_conversation_as_prompt, get_prompts, Prompt},
+    prompt::{
+        get_last_conversation_as_prompt, get_prompts, load_conversation, save_conversation, Prompt,
+    },
+    settings::get_settings,
 };
+use context::RagMode;
+use export::ExportFormat;
+use import::ImportFormat;
 use prompt_customization::customize_prompt;
 
-use clap::{Args, Parser};
+use clap::{Args, Parser, Subcommand};
 use log::debug;
-use std::fs;
 use std::io::{self, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
 
-use text::process_input_with_request;
+use utils::edit_in_editor;
+
+use text::{cache, cache::ResponseCache, compaction, compare, models, process_input_with_request};
 
 const DEFAULT_PROMPT_NAME: &str = "default";
 
@@ -40,12 +57,16 @@ const DEFAULT_PROMPT_NAME: &str = "default";
 - sc \"explain how to use this program\" -c **/*.md main.py  # use files as context
 
 - git diff | sc \"summarize the changes\"  # pipe data in
+- sc commit-msg --git-diff=staged          # or use the git helpers
 
 - cat en.md | sc \"translate in french\" >> fr.md   # write data out
 - sc -e \"use a more informal tone\" -t 2 >> fr.md  # extend the conversation and raise the temprature
-"
+",
+    args_conflicts_with_subcommands = true
 )]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// ref to a prompt template from config or straight input (will use `default` prompt template if input)
     input_or_template_ref: Option<String>,
     /// if the first arg matches a config template, the second will be used as input
@@ -56,17 +77,177 @@ struct Cli {
     /// whether to repeat the input before the output, useful to extend instead of replacing
     #[arg(short, long)]
     repeat_input: bool,
+    /// show which context would be sent and exit without calling the api
+    #[arg(long)]
+    dry_run: bool,
+    /// don't use the response cache for this request
+    #[arg(long, conflicts_with = "refresh")]
+    no_cache: bool,
+    /// ignore any cached answer and cache the new one
+    #[arg(long)]
+    refresh: bool,
+    /// drop the last answer of the conversation and ask again, e.g. with another `-t` or `-m`
+    #[arg(long, conflicts_with_all = ["extend_conversation", "input_or_template_ref"])]
+    retry: bool,
+    /// edit the last message of the conversation in `$EDITOR` and ask again from there
+    #[arg(long, conflicts_with_all = ["extend_conversation", "input_or_template_ref", "retry"])]
+    edit_last: bool,
+    /// remove the last question and its answer from the conversation
+    #[arg(long, conflicts_with_all = ["extend_conversation", "input_or_template_ref", "retry", "edit_last"])]
+    undo: bool,
+    /// number of answers to ask for, they are numbered to pick the one to keep, or printed
+    /// as a json array in non-interactive mode, without the repeated input, the first one
+    /// being kept in the conversation
+    #[arg(short = 'n', long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=16), verbatim_doc_comment)]
+    candidates: u32,
+    /// list the values masked before sending the prompt, redaction is enabled in the settings file
+    #[arg(long)]
+    show_redactions: bool,
+    /// ask for a shell command, show it with its risk and run it in `$SHELL` once confirmed
+    #[arg(long, conflicts_with_all = ["candidates", "repeat_input", "retry", "edit_last", "undo"])]
+    exec: bool,
+    /// with `--exec`, send the output of the command back to have it explained
+    #[arg(long, requires = "exec")]
+    explain: bool,
     #[command(flatten)]
     prompt_params: PromptParams,
 }
 
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// manage the response cache, enabled in the settings file
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+    /// manage the last conversation, the one extended with `-e`
+    Session {
+        #[command(subcommand)]
+        action: SessionAction,
+    },
+    /// render a conversation in markdown, json, html or jsonl for fine-tuning datasets
+    Export {
+        /// conversation file, the last conversation otherwise
+        session: Option<PathBuf>,
+        #[arg(short, long, value_enum, default_value_t = ExportFormat::Md)]
+        format: ExportFormat,
+    },
+    /// replace the last conversation by a markdown transcript or a json or jsonl file of messages,
+    /// to extend it with `-e`
+    Import {
+        file: PathBuf,
+        /// the one matching the file extension otherwise, markdown if none does
+        #[arg(short, long, value_enum)]
+        format: Option<ImportFormat>,
+    },
+    /// send the customized prompt to several models at once, with latencies and token counts
+    Compare {
+        /// comma separated list of `api:model`, `api` for its default model, model aliases
+        /// or models of the api of the prompt
+        #[arg(long, required = true, value_delimiter = ',', verbatim_doc_comment)]
+        models: Vec<String>,
+        /// ref to a prompt template from config or straight input
+        input_or_template_ref: Option<String>,
+        /// if the first arg matches a config template, the second will be used as input
+        input_if_template_ref: Option<String>,
+        /// show the answers in columns instead of one after the other
+        #[arg(long)]
+        side_by_side: bool,
+        /// also write the messages sent and the results to this json file
+        #[arg(long, value_name = "FILE")]
+        report: Option<PathBuf>,
+        #[command(flatten)]
+        prompt_params: Box<PromptParams>,
+    },
+    /// run the test cases of an eval file through their templates and check the answers
+    Eval {
+        /// toml file of `[[case]]` tables
+        file: PathBuf,
+        /// only run the cases whose name contains this
+        #[arg(long)]
+        filter: Option<String>,
+        /// overwrite the stored snapshots with the new answers
+        #[arg(long)]
+        update_snapshots: bool,
+        /// where the snapshots are stored, `<file stem>.snapshots` next to the eval file otherwise
+        #[arg(long, value_name = "DIR")]
+        snapshots: Option<PathBuf>,
+    },
+    /// serve an openai compatible chat completions endpoint and the templates over http
+    Serve {
+        #[arg(short, long, default_value_t = 8080)]
+        port: u16,
+        /// address to listen on, other machines can reach the keys if it isn't local
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+    },
+    /// language server over stdio offering the templates as code actions on the selection
+    Lsp {
+        /// also send the diagnostics of the selection, such as compiler errors
+        #[arg(long)]
+        diagnostics: bool,
+    },
+    /// embed files into a local index for `sc search`, unchanged files are skipped
+    Embed {
+        /// glob patterns or list of files to embed
+        #[arg(required = true)]
+        files: Vec<String>,
+        /// api used for the embeddings, the one of the default prompt otherwise
+        #[arg(long)]
+        api: Option<Api>,
+        /// embedding model, the `embedding_model` of the api config otherwise
+        #[arg(short, long)]
+        model: Option<String>,
+        /// index file, the one of the current directory in the config dir otherwise
+        #[arg(long)]
+        index: Option<PathBuf>,
+    },
+    /// find the chunks of the indexed files closest to the query
+    Search {
+        query: String,
+        /// number of results
+        #[arg(short = 'k', long, default_value_t = 5)]
+        top_k: usize,
+        /// only print the paths of the matching files, e.g. to use them with `-c`
+        #[arg(long)]
+        files: bool,
+        /// index file, the one of the current directory in the config dir otherwise
+        #[arg(long)]
+        index: Option<PathBuf>,
+    },
+    /// list the models of every configured api and check the configured ones exist
+    Models {
+        /// only list the models of this api
+        #[arg(long)]
+        api: Option<Api>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum SessionAction {
+    /// summarize the older turns of the last conversation, keeping the system messages
+    Compact {
+        /// number of last turns kept as they are, `keep_last_turns` of the settings otherwise
+        #[arg(long)]
+        keep: Option<usize>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum CacheAction {
+    /// remove every cached answer
+    Clear,
+    /// show the number and size of the cached answers
+    Stats,
+}
+
 #[derive(Debug, Default, Args)]
 #[group(id = "prompt_params")]
 struct PromptParams {
     /// overrides which api to hit
     #[arg(long)]
     api: Option<Api>,
-    /// overrides which model (of the api) to use
+    /// overrides which model (of the api) to use, or an alias from the settings
     #[arg(short, long)]
     model: Option<String>,
     /// higher temperature  means answer further from the average
@@ -75,10 +256,52 @@ struct PromptParams {
     /// max number of chars to include, ask for user approval if more, 0 = no limit
     #[arg(short = 'l', long)]
     char_limit: Option<u32>,
+    /// max number of tokens of the answer
+    #[arg(long)]
+    max_tokens: Option<u32>,
+    /// only sample among the most likely tokens making up this probability mass
+    #[arg(long)]
+    top_p: Option<f32>,
+    /// only sample among the k most likely tokens (anthropic and ollama)
+    #[arg(long)]
+    top_k: Option<u32>,
+    /// stop the generation at this sequence, can be repeated
+    #[arg(long)]
+    stop: Vec<String>,
+    /// seed for reproducible sampling, when supported
+    #[arg(long)]
+    seed: Option<i64>,
+    /// penalize tokens already present, higher means more new topics
+    #[arg(long)]
+    presence_penalty: Option<f32>,
+    /// when the answer is cut by the max tokens, ask for the rest and stitch it
+    #[arg(long)]
+    auto_continue: bool,
+    /// json object merged into the request body, for parameters smartcat doesn't handle
+    #[arg(long, value_name = "JSON", value_parser = parse_json_object)]
+    extra_body: Option<serde_json::Map<String, serde_json::Value>>,
     /// glob patterns or list of files to use the content as context
     /// make sure it's the last arg.
     #[arg(short, long, num_args= 1.., value_delimiter = ' ', verbatim_doc_comment)]
     context: Vec<String>,
+    /// max number of chars of context to include (~4 chars per token), the file reaching
+    /// the limit is truncated and the following ones are left out, 0 = no limit
+    #[arg(long, value_name = "CHARS", verbatim_doc_comment)]
+    context_budget: Option<usize>,
+    /// only add the chunks of the context files most relevant to the instruction, ranked
+    /// with bm25 or with the embeddings of `sc embed`, within the budget (20000 by default)
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "bm25", value_name = "bm25|embeddings", verbatim_doc_comment)]
+    rag: Option<RagMode>,
+    /// add `git diff` as context, `--git-diff=staged` for the staged changes
+    /// or `--git-diff=<ref>` to diff against a branch, commit or range
+    #[arg(long, num_args = 0..=1, require_equals = true, value_name = "staged|REF", verbatim_doc_comment)]
+    git_diff: Option<Option<String>>,
+    /// add the last N commits as context
+    #[arg(long, value_name = "N")]
+    git_log: Option<u32>,
+    /// add the content of the files changed since the last commit as context
+    #[arg(long)]
+    git_files_changed: bool,
 }
 
 fn main() {
@@ -86,11 +309,11 @@ fn main() {
 
     let stdin = io::stdin();
     let mut output = io::stdout();
-    let mut input = String::new();
 
     // case for testing
     // TODO: mock API and actually use the real processing
     if std::env::var("SMARTCAT_TEST").unwrap_or_default() == "1" {
+        let mut input = String::new();
         if let Err(e) = stdin
             .lock()
             .read_to_string(&mut input)
@@ -107,16 +330,48 @@ fn main() {
 
     debug!("args: {:?}", args);
 
+    // stdout is the channel of the language server, it sets up the config files silently
+    if let Some(Command::Lsp { diagnostics }) = args.command {
+        if let Err(e) = lsp::run(diagnostics) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     config::ensure_config_files()
         .expect("Unable to verify that the config files exist or to generate new ones.");
 
-    let is_piped = !stdin.is_terminal();
+    if let Some(command) = args.command {
+        run_command(command);
+        return;
+    }
+
+    if args.undo {
+        if let Err(e) = undo_last_exchange() {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let resend_last_turn = args.retry || args.edit_last;
+    let is_piped = !stdin.is_terminal() && !resend_last_turn;
     let mut prompt_customizaton_text: Option<String> = None;
 
-    let prompt: Prompt = if !args.extend_conversation {
+    let mut prompt: Prompt = if resend_last_turn {
+        get_last_turn_to_resend(args.edit_last).unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        })
+    } else if !args.extend_conversation {
         // try to get prompt matching the first arg and use second arg as customization text
         // if it doesn't use default prompt and treat that first arg as customization text
-        get_default_and_or_custom_prompt(&args, &mut prompt_customizaton_text)
+        get_default_and_or_custom_prompt(
+            &args.input_or_template_ref,
+            &args.input_if_template_ref,
+            &mut prompt_customizaton_text,
+        )
     } else {
         prompt_customizaton_text = args.input_or_template_ref;
         if args.input_if_template_ref.is_some() {
@@ -128,31 +383,85 @@ fn main() {
         get_last_conversation_as_prompt()
     };
 
-    // if no text was piped, use the custom prompt as input
-    if is_piped {
-        stdin.lock().read_to_string(&mut input).unwrap();
-    }
-
-    if input.is_empty() {
-        input.push_str(&prompt_customizaton_text.unwrap_or_default());
-        prompt_customizaton_text = None;
-    }
+    let input = read_input(is_piped, &mut prompt_customizaton_text);
 
     debug!("input: {}", input);
     debug!("promt_customization_text: {:?}", prompt_customizaton_text);
 
-    let prompt = customize_prompt(prompt, &args.prompt_params, prompt_customizaton_text);
+    // the instruction is what the context is ranked against with `--rag`
+    let rag_query = prompt_customizaton_text
+        .clone()
+        .unwrap_or_else(|| input.clone());
+
+    if args.dry_run {
+        print!(
+            "{}",
+            context::gather_context(&args.prompt_params, &rag_query, true)
+        );
+        std::process::exit(0);
+    }
+
+    // summarize the older turns of a long conversation before adding to it
+    if args.extend_conversation {
+        compaction::compact_if_too_long(&mut prompt);
+    }
+
+    let mut prompt = customize_prompt(
+        prompt,
+        &args.prompt_params,
+        prompt_customizaton_text,
+        &rag_query,
+    );
+    prompt.resolve_aliases(&get_settings().aliases, args.prompt_params.api.as_ref());
+    // right before the input, to only get the command back, and left out of the saved conversation
+    let command_request_index = args.exec.then(|| prompt.messages.len() - 1);
+    if let Some(index) = command_request_index {
+        prompt
+            .messages
+            .insert(index, shell::command_request(&shell::user_shell()));
+    }
 
     debug!("{:?}", prompt);
 
-    match process_input_with_request(prompt, input, &mut output, args.repeat_input) {
-        Ok(prompt) => {
-            let toml_string =
-                toml::to_string(&prompt).expect("Failed to serialize prompt after response.");
-            let mut file = fs::File::create(conversation_file_path())
-                .expect("Failed to create the conversation save file.");
-            file.write_all(toml_string.as_bytes())
-                .expect("Failed to write to the conversation file.");
+    // asking again is meant to get a new answer, not the cached one,
+    // and several answers to the same request would all be the cached one
+    let cache = ResponseCache::new(
+        &get_settings().cache,
+        args.no_cache || args.candidates > 1,
+        args.refresh || resend_last_turn,
+    );
+
+    // the command is shown on stderr, to keep stdout for what it prints
+    let mut command_answer = Vec::new();
+    let answer_output: &mut dyn Write = if args.exec {
+        &mut command_answer
+    } else {
+        &mut output
+    };
+    match process_input_with_request(
+        prompt,
+        input,
+        answer_output,
+        args.repeat_input,
+        &cache,
+        args.show_redactions,
+        args.candidates as usize,
+    ) {
+        Ok(mut prompt) => {
+            if let Some(index) = command_request_index {
+                prompt.messages.remove(index);
+            }
+            save_conversation(&prompt).expect("Failed to write to the conversation file.");
+            if args.exec {
+                let answer = String::from_utf8_lossy(&command_answer);
+                match execute_command(prompt, &answer, args.explain, &cache) {
+                    Ok(code) => std::process::exit(code),
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
         }
         Err(e) => {
             eprintln!("Error: {}", e);
@@ -162,13 +471,382 @@ fn main() {
     }
 }
 
+/// Show the command of the answer with its risk and run it once confirmed, then have its output
+/// explained if asked. Returns the exit code of the command.
+fn execute_command(
+    mut prompt: Prompt,
+    answer: &str,
+    explain: bool,
+    cache: &ResponseCache,
+) -> Result<i32, Box<dyn std::error::Error>> {
+    let command = shell::extract_command(answer)?;
+    let assessment = shell::assess(&command, &std::env::current_dir()?);
+    eprintln!("{}\n{}", command, assessment);
+    if !shell::confirm(assessment.risk)? {
+        eprintln!("Not run.");
+        return Ok(0);
+    }
+
+    let shell = shell::user_shell();
+    if !explain {
+        return Ok(shell::run(&shell, &command)?.code().unwrap_or(1));
+    }
+    let output = shell::run_captured(&shell, &command)?;
+    io::stdout().write_all(&output.stdout)?;
+    io::stderr().write_all(&output.stderr)?;
+
+    prompt
+        .messages
+        .push(shell::explanation_request(&command, &output));
+    let redactions = text::insert_input(&mut prompt, "", false);
+    text::validate_prompt_size(&prompt);
+    let explanation = text::request_answer(&prompt, cache)?;
+    let content = if get_settings().redaction.restore {
+        redactions.restore(&explanation.content)
+    } else {
+        explanation.content.clone()
+    };
+    println!("\n{}", content.trim_end());
+    prompt.messages.push(explanation);
+    save_conversation(&prompt)?;
+    Ok(output.status.code().unwrap_or(1))
+}
+
+/// the piped input, or the customization text used as input when nothing was piped
+fn read_input(is_piped: bool, prompt_customization_text: &mut Option<String>) -> String {
+    let mut input = String::new();
+    if is_piped {
+        io::stdin().lock().read_to_string(&mut input).unwrap();
+    }
+    if input.is_empty() {
+        input.push_str(&prompt_customization_text.take().unwrap_or_default());
+    }
+    input
+}
+
+fn compare_models(
+    models: &[String],
+    input_or_template_ref: &Option<String>,
+    input_if_template_ref: &Option<String>,
+    prompt_params: &PromptParams,
+    side_by_side: bool,
+    report: Option<PathBuf>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut prompt_customization_text = None;
+    let prompt = get_default_and_or_custom_prompt(
+        input_or_template_ref,
+        input_if_template_ref,
+        &mut prompt_customization_text,
+    );
+    let input = read_input(!io::stdin().is_terminal(), &mut prompt_customization_text);
+    let rag_query = prompt_customization_text
+        .clone()
+        .unwrap_or_else(|| input.clone());
+
+    let mut prompt = customize_prompt(prompt, prompt_params, prompt_customization_text, &rag_query);
+    let aliases = get_settings().aliases;
+    let backends = models
+        .iter()
+        .map(|spec| compare::parse_backend(spec, &prompt.api, &aliases))
+        .collect::<Result<Vec<_>, _>>()?;
+    let redactions = text::insert_input(&mut prompt, &input, false);
+
+    let comparisons = compare::compare(&prompt, &backends, &redactions);
+    if side_by_side {
+        let width = std::env::var("COLUMNS")
+            .ok()
+            .and_then(|columns| columns.parse().ok())
+            .unwrap_or(160);
+        print!("{}", compare::side_by_side_view(&comparisons, width));
+    } else {
+        print!("{}", compare::sequential_view(&comparisons));
+    }
+    if let Some(report) = report {
+        std::fs::write(&report, compare::report_json(&prompt, &comparisons))?;
+    }
+    Ok(())
+}
+
+fn run_eval(
+    file: &std::path::Path,
+    options: &eval::EvalOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let results = eval::run(file, options, |result| {
+        let status = if result.passed() { "PASS" } else { "FAIL" };
+        let snapshot = if result.snapshot_written {
+            ", snapshot written"
+        } else {
+            ""
+        };
+        println!(
+            "{}  {} ({:.2}s{})",
+            status,
+            result.name,
+            result.duration.as_secs_f32(),
+            snapshot
+        );
+        for failure in &result.failures {
+            println!("      {}", failure.replace('\n', "\n      "));
+        }
+    })?;
+    let failed = results.iter().filter(|result| !result.passed()).count();
+    println!("\n{} passed, {} failed", results.len() - failed, failed);
+    if failed > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// the last conversation without its last answer, ending with the user message to send again
+fn get_last_turn_to_resend(edit: bool) -> Result<Prompt, Box<dyn std::error::Error>> {
+    let mut prompt = get_last_conversation_as_prompt();
+    prompt.pop_last_answer();
+    let last_message = prompt
+        .messages
+        .last_mut()
+        .filter(|message| message.role == "user")
+        .ok_or("the conversation doesn't end with a question to ask again")?;
+    if edit {
+        let content = edit_in_editor(&last_message.content)?;
+        if content.trim().is_empty() {
+            return Err("the edited message is empty, nothing was sent".into());
+        }
+        last_message.content = content;
+    }
+    Ok(prompt)
+}
+
+fn undo_last_exchange() -> Result<(), Box<dyn std::error::Error>> {
+    let mut prompt = get_last_conversation_as_prompt();
+    if !prompt.pop_last_exchange() {
+        println!("Nothing to undo, the conversation has no question left.");
+        return Ok(());
+    }
+    save_conversation(&prompt)?;
+    println!(
+        "Removed the last exchange, {} message(s) left.",
+        prompt.messages.len()
+    );
+    Ok(())
+}
+
+fn compact_conversation(keep: Option<usize>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut prompt = get_last_conversation_as_prompt();
+    let keep = keep.unwrap_or(get_settings().compaction.keep_last_turns);
+    match compaction::compact(&mut prompt, keep)? {
+        Some(report) => {
+            save_conversation(&prompt)?;
+            println!(
+                "Summarized {} turn(s), the conversation went from {} to {} chars.",
+                report.summarized_turns, report.chars_before, report.chars_after
+            );
+        }
+        None => println!(
+            "Nothing to compact, the conversation has {} turn(s) or fewer.",
+            keep
+        ),
+    }
+    Ok(())
+}
+
+fn import_conversation(
+    file: &std::path::Path,
+    format: Option<ImportFormat>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let messages = import::load_messages(file, format)?;
+    // answered with the api and model of the default prompt when extended
+    let default_prompt = get_prompts()
+        .remove(DEFAULT_PROMPT_NAME)
+        .unwrap_or_default();
+    let prompt = Prompt {
+        api: default_prompt.api,
+        model: default_prompt.model,
+        messages,
+        ..Prompt::empty()
+    };
+    save_conversation(&prompt)?;
+    println!(
+        "Imported {} message(s), extend the conversation with `sc -e`.",
+        prompt.messages.len()
+    );
+    Ok(())
+}
+
+fn parse_json_object(value: &str) -> Result<serde_json::Map<String, serde_json::Value>, String> {
+    serde_json::from_str(value).map_err(|e| format!("expected a json object: {}", e))
+}
+
+fn run_command(command: Command) {
+    let result: Result<(), Box<dyn std::error::Error>> = match command {
+        Command::Cache {
+            action: CacheAction::Clear,
+        } => cache::clear()
+            .map(|count| println!("Removed {} cached answer(s).", count))
+            .map_err(Into::into),
+        Command::Cache {
+            action: CacheAction::Stats,
+        } => cache::stats()
+            .map(|stats| {
+                println!(
+                    "{} cached answer(s), {} expired, {} KB",
+                    stats.entries,
+                    stats.expired,
+                    stats.size_bytes / 1024
+                )
+            })
+            .map_err(Into::into),
+        Command::Session {
+            action: SessionAction::Compact { keep },
+        } => compact_conversation(keep),
+        Command::Export { session, format } => {
+            let prompt = match session {
+                Some(path) => load_conversation(&path),
+                None => get_last_conversation_as_prompt(),
+            };
+            print!("{}", export::export(&prompt, format));
+            Ok(())
+        }
+        Command::Import { file, format } => import_conversation(&file, format),
+        Command::Compare {
+            models,
+            input_or_template_ref,
+            input_if_template_ref,
+            side_by_side,
+            report,
+            prompt_params,
+        } => compare_models(
+            &models,
+            &input_or_template_ref,
+            &input_if_template_ref,
+            &prompt_params,
+            side_by_side,
+            report,
+        ),
+        Command::Eval {
+            file,
+            filter,
+            update_snapshots,
+            snapshots,
+        } => run_eval(
+            &file,
+            &eval::EvalOptions {
+                filter,
+                update_snapshots,
+                snapshots_dir: snapshots,
+            },
+        ),
+        Command::Serve { port, host } => server::serve(&host, port).map_err(Into::into),
+        Command::Lsp { .. } => {
+            unreachable!("the language server is started before the config check")
+        }
+        Command::Models { api } => list_available_models(api),
+        Command::Embed {
+            files,
+            api,
+            model,
+            index,
+        } => {
+            let api =
+                api.unwrap_or_else(|| get_prompts().remove("default").unwrap_or_default().api);
+            let index = index.unwrap_or_else(embeddings::project_index_path);
+            embeddings::embed(&files, api, model, &index)
+                .map(|report| {
+                    println!(
+                        "Embedded {} file(s) in {} chunk(s), {} unchanged, {} skipped.",
+                        report.embedded_files,
+                        report.embedded_chunks,
+                        report.unchanged_files,
+                        report.skipped.len()
+                    )
+                })
+                .map_err(Into::into)
+        }
+        Command::Search {
+            query,
+            top_k,
+            files,
+            index,
+        } => embeddings::search(
+            &query,
+            top_k,
+            &index.unwrap_or_else(embeddings::project_index_path),
+        )
+        .map(|results| {
+            if files {
+                for path in embeddings::result_files(&results) {
+                    println!("{}", path);
+                }
+            } else {
+                for result in results {
+                    println!(
+                        "{:.3}  {}:{}-{}",
+                        result.score, result.path, result.start_line, result.end_line
+                    );
+                }
+            }
+        })
+        .map_err(Into::into),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn list_available_models(api: Option<Api>) -> Result<(), Box<dyn std::error::Error>> {
+    let api_configs = get_api_configs();
+    let aliases = get_settings().aliases;
+    let prompts = get_prompts()
+        .into_iter()
+        .map(|(name, mut prompt)| {
+            prompt.resolve_aliases(&aliases, None);
+            (name, prompt)
+        })
+        .collect();
+
+    if let Some(api) = api {
+        let api_config = api_configs
+            .get(&api.to_string())
+            .ok_or(format!("{} is not configured in the api config file", api))?;
+        let models = models::list_models(&api, api_config)?
+            .ok_or(format!("{} can't list its models", api))?;
+        for model in models.iter() {
+            println!("{}", model);
+        }
+        models::check_configured_models(&api, api_config, &prompts, &models);
+        return Ok(());
+    }
+
+    let mut api_configs: Vec<_> = api_configs
+        .into_iter()
+        .filter_map(|(name, api_config)| Some((Api::from_str(&name).ok()?, api_config)))
+        .filter(|(api, api_config)| *api == Api::Ollama || api_config.has_api_key_source())
+        .collect();
+    api_configs.sort_by_key(|(api, _)| api.to_string());
+    for (api, api_config) in api_configs {
+        match models::list_models(&api, &api_config) {
+            Ok(Some(models)) => {
+                println!("{}:", api);
+                for model in models.iter() {
+                    println!("  {}", model);
+                }
+                models::check_configured_models(&api, &api_config, &prompts, &models);
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("{}: {}", api, e),
+        }
+    }
+    Ok(())
+}
+
 /// Fills prompt_customization_text with the correct part of the args
 /// first arg -> input_or_config_ref
 /// second arg -> input_if_config_ref
 /// if first arg is a prompt name, get that prompt and use second arg as input
 /// if not, use default prompt, use first arg as input and forbid second arg
 fn get_default_and_or_custom_prompt(
-    args: &Cli,
+    input_or_template_ref: &Option<String>,
+    input_if_template_ref: &Option<String>,
     prompt_customization_text: &mut Option<String>,
 ) -> Prompt {
     let mut prompts = get_prompts();
@@ -178,20 +856,19 @@ fn get_default_and_or_custom_prompt(
         &available_prompts
     );
 
-    let input_or_config_ref = args
-        .input_or_template_ref
+    let input_or_config_ref = input_or_template_ref
         .clone()
         .unwrap_or_else(|| String::from("default"));
 
     if let Some(prompt) = prompts.remove(&input_or_config_ref) {
-        if args.input_if_template_ref.is_some() {
+        if input_if_template_ref.is_some() {
             // first arg matching a prompt and second one is customization
-            *prompt_customization_text = args.input_if_template_ref.clone()
+            *prompt_customization_text = input_if_template_ref.clone()
         }
         prompt
     } else {
         *prompt_customization_text = Some(input_or_config_ref);
-        if args.input_if_template_ref.is_some() {
+        if input_if_template_ref.is_some() {
             // first arg isn't a prompt and a second one was provided
             panic!(
                 "Invalid parameters, either provide a valid ref to a config prompt then an input, or only an input.\n\
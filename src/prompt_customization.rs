@@ -1,19 +1,24 @@
-use glob::glob;
 use log::debug;
-use std::fs;
+use std::collections::HashMap;
 
 use crate::{
     config::{
         prompt::{Message, Prompt},
         PLACEHOLDER_TOKEN,
     },
+    context::gather_context,
+    import::load_messages,
     PromptParams,
 };
 
+/// starts the system message of the context files, added before the input of each request
+pub const CONTEXT_HEADER: &str = "files content for context:\n\n";
+
 pub fn customize_prompt(
     mut prompt: Prompt,
     prompt_params: &PromptParams,
     custom_prompt: Option<String>,
+    rag_query: &str,
 ) -> Prompt {
     debug!("pre-customization prompt {:?}", prompt);
 
@@ -27,28 +32,45 @@ pub fn customize_prompt(
     if prompt_params.char_limit.is_some() {
         prompt.char_limit = prompt_params.char_limit;
     }
+    if prompt_params.max_tokens.is_some() {
+        prompt.max_tokens = prompt_params.max_tokens;
+    }
+    if prompt_params.top_p.is_some() {
+        prompt.top_p = prompt_params.top_p;
+    }
+    if prompt_params.top_k.is_some() {
+        prompt.top_k = prompt_params.top_k;
+    }
+    if !prompt_params.stop.is_empty() {
+        prompt.stop = prompt_params.stop.clone();
+    }
+    if prompt_params.seed.is_some() {
+        prompt.seed = prompt_params.seed;
+    }
+    if prompt_params.presence_penalty.is_some() {
+        prompt.presence_penalty = prompt_params.presence_penalty;
+    }
+    if prompt_params.auto_continue {
+        prompt.auto_continue = true;
+    }
+    if let Some(extra_body) = &prompt_params.extra_body {
+        // merged with the one of the template, the args win
+        prompt
+            .extra_body
+            .get_or_insert_with(Default::default)
+            .extend(extra_body.clone());
+    }
 
     // Collect the content of all the context files
-    let context = prompt_params
-        .context
-        .iter()
-        .flat_map(|glob_pattern| {
-            glob(glob_pattern)
-                .expect("Failed to read glob pattern")
-                .filter_map(Result::ok)
-                .map(|path| {
-                    fs::read_to_string(&path)
-                        .ok()
-                        .map(|content| format!("{}:\n```\n{}\n```\n", path.display(), content))
-                })
-        })
-        .flatten()
-        .collect::<String>();
+    let context = gather_context(prompt_params, rag_query, false);
+    for warning in &context.warnings {
+        eprintln!("Warning: {}.", warning);
+    }
 
     if !context.is_empty() {
         prompt.messages.push(Message::system(&format!(
-            "files content for context:\n\n{}",
-            context
+            "{}{}",
+            CONTEXT_HEADER, context.content
         )));
     }
 
@@ -86,6 +108,13 @@ pub fn customize_prompt(
             prompt.temperature = prompt_params.temperature;
         }
     }
+    // few-shot examples go right before the input, only once so that `-e` doesn't repeat them
+    if let Some(examples_path) = prompt.examples_path() {
+        let examples = load_messages(&examples_path, None)
+            .unwrap_or_else(|error| panic!("Could not load the examples file, {}", error));
+        prompt.messages.extend(examples);
+        prompt.examples_file = None;
+    }
     prompt.messages.push(last_message);
 
     debug!("post-customization prompt {:?}", prompt);
@@ -93,6 +122,15 @@ pub fn customize_prompt(
     prompt
 }
 
+/// replace the `#[<name>]` placeholders of the messages, as `#[<input>]` is by the input
+pub fn insert_vars(messages: &mut [Message], vars: &HashMap<String, String>) {
+    for message in messages.iter_mut() {
+        for (name, value) in vars {
+            message.content = message.content.replace(&format!("#[<{}>]", name), value);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::config::api::Api;
@@ -105,7 +143,7 @@ mod tests {
         let prompt = Prompt::default();
         let prompt_params = PromptParams::default();
 
-        let customized = customize_prompt(prompt, &prompt_params, None);
+        let customized = customize_prompt(prompt, &prompt_params, None, "");
         let default_prompt = Prompt::empty();
 
         assert_eq!(customized.api, default_prompt.api);
@@ -139,7 +177,7 @@ mod tests {
             ..PromptParams::default()
         };
 
-        let customized = customize_prompt(prompt, &prompt_params, None);
+        let customized = customize_prompt(prompt, &prompt_params, None, "");
         let default_prompt = Prompt::empty();
 
         assert_eq!(customized.api, Api::AnotherApiForTests);
@@ -154,7 +192,7 @@ mod tests {
             ..PromptParams::default()
         };
 
-        let customized = customize_prompt(prompt, &prompt_params, None);
+        let customized = customize_prompt(prompt, &prompt_params, None, "");
 
         let default_prompt = Prompt::empty();
         assert_eq!(customized.model, prompt_params.model);
@@ -167,7 +205,7 @@ mod tests {
         let prompt_params = PromptParams::default();
         let custom_prompt = Some("test_command".to_owned());
 
-        let customized = customize_prompt(prompt, &prompt_params, custom_prompt);
+        let customized = customize_prompt(prompt, &prompt_params, custom_prompt, "");
 
         assert!(customized
             .messages
@@ -187,7 +225,7 @@ mod tests {
             ..PromptParams::default()
         };
 
-        let customized = customize_prompt(prompt, &prompt_params, None);
+        let customized = customize_prompt(prompt, &prompt_params, None, "");
 
         assert_eq!(
             customized.messages[0].content,
@@ -200,6 +238,52 @@ mod tests {
         assert_eq!(customized.messages[0].role, "system");
     }
 
+    #[test]
+    fn test_customize_prompt_with_examples_file() {
+        let mut examples_file = tempfile::Builder::new()
+            .suffix(".jsonl")
+            .tempfile()
+            .unwrap();
+        examples_file
+            .write_all(b"{\"input\": \"1 + 1\", \"output\": \"2\"}\n")
+            .unwrap();
+        let prompt = Prompt {
+            messages: vec![Message::system("compute")],
+            examples_file: Some(examples_file.path().to_owned()),
+            ..Prompt::empty()
+        };
+
+        let customized = customize_prompt(
+            prompt,
+            &PromptParams::default(),
+            Some("show your work".to_owned()),
+            "",
+        );
+
+        assert_eq!(
+            customized.messages,
+            vec![
+                Message::system("compute"),
+                Message::user("1 + 1"),
+                Message::assistant("2"),
+                Message::user(&format!("show your work{}", PLACEHOLDER_TOKEN)),
+            ]
+        );
+        assert_eq!(customized.examples_file, None);
+    }
+
+    #[test]
+    fn test_insert_vars() {
+        let mut messages = vec![Message::system("answer in #[<language>]")];
+
+        insert_vars(
+            &mut messages,
+            &HashMap::from([("language".to_string(), "french".to_string())]),
+        );
+
+        assert_eq!(messages, vec![Message::system("answer in french")]);
+    }
+
     #[test]
     fn test_customize_prompt_temperature_override() {
         let prompt = Prompt::empty();
@@ -208,7 +292,7 @@ mod tests {
             ..PromptParams::default()
         };
 
-        let customized = customize_prompt(prompt, &prompt_params, None);
+        let customized = customize_prompt(prompt, &prompt_params, None, "");
 
         assert_eq!(customized.temperature, Some(42.));
     }
@@ -226,10 +310,26 @@ mod tests {
             context: vec![context_file.path().to_str().unwrap().to_owned()],
             temperature: Some(42.),
             char_limit: Some(50_000),
+            context_budget: None,
+            git_diff: None,
+            git_log: None,
+            git_files_changed: false,
+            rag: None,
+            max_tokens: Some(100),
+            top_p: Some(0.9),
+            top_k: Some(40),
+            stop: vec!["END".to_owned()],
+            seed: Some(7),
+            presence_penalty: Some(0.5),
+            auto_continue: true,
+            extra_body: Some(serde_json::Map::from_iter([(
+                "user".to_owned(),
+                serde_json::Value::from("test"),
+            )])),
         };
         let custom_prompt = Some("test_command_override".to_owned());
 
-        let customized = customize_prompt(prompt, &prompt_params, custom_prompt.clone());
+        let customized = customize_prompt(prompt, &prompt_params, custom_prompt.clone(), "");
 
         // Mandatory fields
         assert_eq!(customized.api, prompt_params.api.unwrap());
@@ -241,6 +341,14 @@ mod tests {
         // Optional fields
         assert_eq!(customized.model, prompt_params.model);
         assert_eq!(customized.temperature, prompt_params.temperature);
+        assert_eq!(customized.max_tokens, prompt_params.max_tokens);
+        assert_eq!(customized.top_p, prompt_params.top_p);
+        assert_eq!(customized.top_k, prompt_params.top_k);
+        assert_eq!(customized.stop, prompt_params.stop);
+        assert_eq!(customized.seed, prompt_params.seed);
+        assert_eq!(customized.presence_penalty, prompt_params.presence_penalty);
+        assert_eq!(customized.extra_body, prompt_params.extra_body);
+        assert!(customized.auto_continue);
         assert_eq!(
             customized.messages[0].content,
             format!(
@@ -0,0 +1,707 @@
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::time::Duration;
+
+use crate::config::{
+    api::{get_api_configs, Api},
+    prompt::{get_prompts, Backend, Message, Prompt, TokenUsage},
+    settings::get_settings,
+};
+use crate::import::{JsonMessage, ROLES};
+use crate::prompt_customization::{customize_prompt, insert_vars};
+use crate::text::{
+    cache::ResponseCache, compare::parse_backend, insert_input, open_stream, redaction::Redactions,
+    request_answer,
+};
+use crate::utils::{now_seconds, IS_NONINTERACTIVE_ENV_VAR};
+use crate::{PromptParams, DEFAULT_PROMPT_NAME};
+
+/// requests bodies larger than this are refused
+const MAX_BODY_BYTES: usize = 10 * 1024 * 1024;
+/// a client sending nothing for this long is dropped
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, PartialEq)]
+struct Request {
+    method: String,
+    path: String,
+    /// by lowercase name
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+/// an error answered in the openai format
+#[derive(Debug, PartialEq)]
+struct HttpError {
+    status: u16,
+    message: String,
+}
+
+impl HttpError {
+    fn new(status: u16, message: impl ToString) -> HttpError {
+        HttpError {
+            status,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// the openai chat completion request, the parameters smartcat doesn't handle are ignored
+#[derive(Debug, Deserialize)]
+struct ChatRequest {
+    /// `api:model`, `api`, an alias or a model of the api of the default prompt
+    #[serde(default)]
+    model: Option<String>,
+    messages: Vec<JsonMessage>,
+    #[serde(default)]
+    temperature: Option<f32>,
+    #[serde(default)]
+    max_tokens: Option<u32>,
+    #[serde(default)]
+    top_p: Option<f32>,
+    /// a string or a list of strings
+    #[serde(default)]
+    stop: Option<Value>,
+    #[serde(default)]
+    seed: Option<i64>,
+    #[serde(default)]
+    presence_penalty: Option<f32>,
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TemplateRequest {
+    #[serde(default)]
+    input: String,
+    /// as the second arg of `sc <template> <instruction>`
+    #[serde(default)]
+    instruction: Option<String>,
+    /// values replacing the `#[<name>]` placeholders of the template
+    #[serde(default)]
+    vars: HashMap<String, String>,
+    #[serde(default)]
+    api: Option<Api>,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    temperature: Option<f32>,
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Completion {
+    id: String,
+    object: &'static str,
+    created: u64,
+    model: String,
+    choices: Vec<Choice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<CompletionUsage>,
+}
+
+#[derive(Debug, Serialize)]
+struct Choice {
+    index: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<ChoiceMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delta: Option<ChoiceMessage>,
+    finish_reason: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChoiceMessage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CompletionUsage {
+    prompt_tokens: u32,
+    completion_tokens: u32,
+    total_tokens: u32,
+}
+
+impl From<TokenUsage> for CompletionUsage {
+    fn from(usage: TokenUsage) -> Self {
+        CompletionUsage {
+            prompt_tokens: usage.input_tokens,
+            completion_tokens: usage.output_tokens,
+            total_tokens: usage.input_tokens + usage.output_tokens,
+        }
+    }
+}
+
+/// Answer the requests one thread per connection, the connection is closed after each answer.
+pub fn serve(host: &str, port: u16) -> io::Result<()> {
+    // the requests are answered in the background, nothing can be asked on the terminal
+    std::env::set_var(IS_NONINTERACTIVE_ENV_VAR, "1");
+    let listener = TcpListener::bind((host, port))?;
+    eprintln!("Serving the templates on http://{}", listener.local_addr()?);
+    let loopback = listener.local_addr()?.ip().is_loopback();
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                std::thread::spawn(move || {
+                    if let Err(e) = handle_connection(stream, loopback) {
+                        debug!("connection error: {}", e);
+                    }
+                });
+            }
+            Err(e) => eprintln!("Error: {}", e),
+        }
+    }
+    Ok(())
+}
+
+fn handle_connection(mut stream: TcpStream, loopback: bool) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let request = match read_request(&mut BufReader::new(&stream)) {
+        Ok(request) => request,
+        Err(e) => return write_error(&mut stream, &HttpError::new(400, e)),
+    };
+    debug!("{} {}", request.method, request.path);
+    if let Err(e) = check_client(&request, loopback) {
+        return write_error(&mut stream, &e);
+    }
+
+    // a panic, e.g. a failing `api_key_command`, is answered rather than resetting the connection
+    let result = catch_unwind(AssertUnwindSafe(|| route(&request, &mut stream)))
+        .unwrap_or_else(|_| Err(HttpError::new(500, "the request failed unexpectedly")));
+    match result {
+        Err(e) => write_error(&mut stream, &e),
+        Ok(()) => Ok(()),
+    }
+}
+
+fn route(request: &Request, stream: &mut TcpStream) -> Result<(), HttpError> {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("POST", "/v1/chat/completions") => {
+            parse_body(&request.body).and_then(|chat_request| chat_completion(chat_request, stream))
+        }
+        ("GET", "/templates") => {
+            let mut names: Vec<String> = get_prompts().into_keys().collect();
+            names.sort();
+            write_json(stream, 200, &names).map_err(|e| HttpError::new(500, e))
+        }
+        ("POST", path) if path.starts_with("/templates/") => {
+            parse_body(&request.body).and_then(|template_request| {
+                let name = &path["/templates/".len()..];
+                template_completion(name, template_request, stream)
+            })
+        }
+        _ => Err(HttpError::new(
+            404,
+            format!("no route for {} {}", request.method, request.path),
+        )),
+    }
+}
+
+/// Refuse what browsers send: any page could otherwise spend the keys with a simple request,
+/// or read the answers by rebinding its domain to localhost.
+fn check_client(request: &Request, loopback: bool) -> Result<(), HttpError> {
+    if request.headers.contains_key("origin") {
+        return Err(HttpError::new(
+            403,
+            "requests from browsers aren't accepted",
+        ));
+    }
+    // the host can't be checked when listening on other interfaces on purpose
+    if loopback {
+        let host = request.headers.get("host").map(String::as_str);
+        if !host.map(host_name).is_some_and(is_loopback_name) {
+            return Err(HttpError::new(
+                403,
+                "only requests to localhost are accepted",
+            ));
+        }
+    }
+    if request.method == "POST" {
+        let content_type = request.headers.get("content-type").map(String::as_str);
+        let media_type = content_type.and_then(|value| value.split(';').next());
+        if media_type.map(str::trim) != Some("application/json") {
+            return Err(HttpError::new(415, "the body must be application/json"));
+        }
+    }
+    Ok(())
+}
+
+/// the host of a `Host` header, without the port nor the brackets of an ipv6 address
+fn host_name(host: &str) -> &str {
+    if host.parse::<IpAddr>().is_ok() {
+        return host;
+    }
+    match host.find(']') {
+        Some(end) if host.starts_with('[') => &host[1..end],
+        _ => host.split(':').next().unwrap_or_default(),
+    }
+}
+
+/// `localhost` or a loopback address such as `127.0.0.2` or `::1`
+fn is_loopback_name(name: &str) -> bool {
+    name.eq_ignore_ascii_case("localhost")
+        || name.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// the roles the apis understand, anything else is refused rather than forwarded
+fn check_roles(messages: &[Message]) -> Result<(), HttpError> {
+    match messages
+        .iter()
+        .find(|message| !ROLES.contains(&message.role.as_str()))
+    {
+        Some(message) => Err(HttpError::new(
+            400,
+            format!(
+                "unknown role `{}`, expected one of {:?}",
+                message.role, ROLES
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// the missing api configs would be a panic later on
+fn check_configured(prompt: &Prompt) -> Result<(), HttpError> {
+    let api_configs = get_api_configs();
+    let apis =
+        std::iter::once(&prompt.api).chain(prompt.fallback.iter().map(|backend| &backend.api));
+    for api in apis {
+        if !api_configs.contains_key(&api.to_string()) {
+            return Err(HttpError::new(
+                400,
+                format!("`{}` isn't configured in the api config file", api),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn parse_body<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T, HttpError> {
+    serde_json::from_slice(body).map_err(|e| HttpError::new(400, format!("invalid body: {}", e)))
+}
+
+/// proxy an openai chat completion request to the api named by the model
+fn chat_completion(chat_request: ChatRequest, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let default_prompt = get_prompts()
+        .remove(DEFAULT_PROMPT_NAME)
+        .unwrap_or_default();
+    let backend = match &chat_request.model {
+        Some(model) => parse_backend(model, &default_prompt.api, &get_settings().aliases)
+            .map_err(|e| HttpError::new(400, e))?,
+        None => Backend {
+            api: default_prompt.api.clone(),
+            model: default_prompt.model.clone(),
+        },
+    };
+    let stop = match chat_request.stop {
+        Some(Value::String(stop)) => vec![stop],
+        Some(stop) => serde_json::from_value(stop)
+            .map_err(|_| HttpError::new(400, "`stop` must be a string or a list of strings"))?,
+        None => Vec::new(),
+    };
+    let mut prompt = Prompt {
+        api: backend.api,
+        model: backend.model,
+        messages: chat_request
+            .messages
+            .into_iter()
+            .map(Message::from)
+            .collect(),
+        temperature: chat_request.temperature,
+        max_tokens: chat_request.max_tokens,
+        top_p: chat_request.top_p,
+        stop,
+        seed: chat_request.seed,
+        presence_penalty: chat_request.presence_penalty,
+        ..Prompt::empty()
+    };
+    check_roles(&prompt.messages)?;
+    check_configured(&prompt)?;
+    let redactions = insert_input(&mut prompt, "", false);
+    answer(prompt, chat_request.stream, stream, &redactions)
+}
+
+/// customize the template with the posted input as `sc <template> <instruction>` would
+fn template_completion(
+    name: &str,
+    template_request: TemplateRequest,
+    stream: &mut TcpStream,
+) -> Result<(), HttpError> {
+    let template = get_prompts()
+        .remove(name)
+        .ok_or_else(|| HttpError::new(404, format!("no `{}` prompt template", name)))?;
+
+    let mut input = template_request.input;
+    let mut instruction = template_request.instruction;
+    if input.is_empty() {
+        input = instruction.take().unwrap_or_default();
+    }
+    let prompt_params = PromptParams {
+        api: template_request.api,
+        model: template_request.model,
+        temperature: template_request.temperature,
+        ..PromptParams::default()
+    };
+    let rag_query = instruction.clone().unwrap_or_else(|| input.clone());
+    // an invalid examples file or context panics, the client gets an error rather than a reset
+    let mut prompt = catch_unwind(AssertUnwindSafe(|| {
+        customize_prompt(template, &prompt_params, instruction, &rag_query)
+    }))
+    .map_err(|_| HttpError::new(400, format!("could not customize the `{}` template", name)))?;
+    prompt.resolve_aliases(&get_settings().aliases, prompt_params.api.as_ref());
+    check_configured(&prompt)?;
+    insert_vars(&mut prompt.messages, &template_request.vars);
+    let redactions = insert_input(&mut prompt, &input, false);
+
+    answer(prompt, template_request.stream, stream, &redactions)
+}
+
+/// Send the prompt and write the answer as an openai chat completion. A streamed answer is
+/// forwarded as it comes from the openai-like apis and sent in a single chunk from the others,
+/// or when redacted values have to be restored in it.
+fn answer(
+    prompt: Prompt,
+    streamed: bool,
+    stream: &mut TcpStream,
+    redactions: &Redactions,
+) -> Result<(), HttpError> {
+    let restore = get_settings().redaction.restore && !redactions.is_empty();
+    let model = format!(
+        "{}:{}",
+        prompt.api,
+        prompt.model.clone().unwrap_or_default()
+    );
+    if streamed && !restore {
+        if let Some(mut events) = open_stream(&prompt).map_err(|e| HttpError::new(502, e))? {
+            write_event_stream_headers(stream).map_err(|e| HttpError::new(500, e))?;
+            // the client may close the connection, nothing left to answer then
+            if let Err(e) = io::copy(&mut events, stream) {
+                debug!("stream interrupted: {}", e);
+            }
+            return Ok(());
+        }
+    }
+
+    let cache = ResponseCache::new(&get_settings().cache, false, false);
+    let message = request_answer(&prompt, &cache).map_err(|e| HttpError::new(502, e))?;
+    let content = if restore {
+        redactions.restore(&message.content)
+    } else {
+        message.content
+    };
+    let usage = message.metadata.and_then(|metadata| metadata.usage);
+
+    let written = if streamed {
+        write_event_stream_headers(stream).and_then(|_| {
+            for chunk in completion_chunks(&model, content) {
+                write!(stream, "data: {}\n\n", serde_json::to_string(&chunk)?)?;
+            }
+            write!(stream, "data: [DONE]\n\n")
+        })
+    } else {
+        write_json(stream, 200, &completion(&model, content, usage))
+    };
+    if let Err(e) = written {
+        debug!("failed to write the answer: {}", e);
+    }
+    Ok(())
+}
+
+fn completion(model: &str, content: String, usage: Option<TokenUsage>) -> Completion {
+    Completion {
+        id: completion_id(),
+        object: "chat.completion",
+        created: now_seconds(),
+        model: model.to_string(),
+        choices: vec![Choice {
+            index: 0,
+            message: Some(ChoiceMessage {
+                role: Some("assistant"),
+                content: Some(content),
+            }),
+            delta: None,
+            finish_reason: Some("stop"),
+        }],
+        usage: usage.map(CompletionUsage::from),
+    }
+}
+
+/// the whole answer in a first chunk, then the finish reason
+fn completion_chunks(model: &str, content: String) -> [Completion; 2] {
+    let id = completion_id();
+    let chunk = |delta, finish_reason| Completion {
+        id: id.clone(),
+        object: "chat.completion.chunk",
+        created: now_seconds(),
+        model: model.to_string(),
+        choices: vec![Choice {
+            index: 0,
+            message: None,
+            delta: Some(delta),
+            finish_reason,
+        }],
+        usage: None,
+    };
+    [
+        chunk(
+            ChoiceMessage {
+                role: Some("assistant"),
+                content: Some(content),
+            },
+            None,
+        ),
+        chunk(
+            ChoiceMessage {
+                role: None,
+                content: None,
+            },
+            Some("stop"),
+        ),
+    ]
+}
+
+fn completion_id() -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.subsec_nanos())
+        .unwrap_or_default();
+    format!("chatcmpl-{}{:09}", now_seconds(), nanos)
+}
+
+fn read_request(reader: &mut impl BufRead) -> Result<Request, String> {
+    let mut request_line = String::new();
+    reader
+        .read_line(&mut request_line)
+        .map_err(|e| e.to_string())?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err("invalid request line".to_string());
+    };
+    // the query string isn't used
+    let path = path.split('?').next().unwrap_or_default().to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).map_err(|e| e.to_string())?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    let content_length = match headers.get("content-length") {
+        Some(length) => length
+            .parse()
+            .map_err(|_| "invalid content-length".to_string())?,
+        None => 0,
+    };
+    if content_length > MAX_BODY_BYTES {
+        return Err(format!("body larger than {} bytes", MAX_BODY_BYTES));
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).map_err(|e| e.to_string())?;
+    Ok(Request {
+        method: method.to_string(),
+        path,
+        headers,
+        body,
+    })
+}
+
+fn status_text(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        415 => "Unsupported Media Type",
+        502 => "Bad Gateway",
+        _ => "Internal Server Error",
+    }
+}
+
+fn write_json<T: Serialize>(stream: &mut impl Write, status: u16, value: &T) -> io::Result<()> {
+    let body = serde_json::to_vec(value)?;
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        status_text(status),
+        body.len()
+    )?;
+    stream.write_all(&body)
+}
+
+fn write_event_stream_headers(stream: &mut impl Write) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"
+    )
+}
+
+fn write_error(stream: &mut impl Write, error: &HttpError) -> io::Result<()> {
+    write_json(
+        stream,
+        error.status,
+        &serde_json::json!({"error": {"message": error.message, "code": error.status}}),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_request() {
+        let raw = "POST /templates/review?verbose=1 HTTP/1.1\r\nHost: localhost\r\ncontent-length: 15\r\n\r\n{\"input\": \"x\"}\nignored";
+
+        assert_eq!(
+            read_request(&mut raw.as_bytes()),
+            Ok(Request {
+                method: "POST".to_string(),
+                path: "/templates/review".to_string(),
+                headers: HashMap::from([
+                    ("host".to_string(), "localhost".to_string()),
+                    ("content-length".to_string(), "15".to_string()),
+                ]),
+                body: b"{\"input\": \"x\"}\n".to_vec(),
+            })
+        );
+        assert!(read_request(&mut "\r\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_check_client() {
+        let request = |headers: &[(&str, &str)]| Request {
+            method: "POST".to_string(),
+            path: "/v1/chat/completions".to_string(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: Vec::new(),
+        };
+        let status = |headers: &[(&str, &str)], loopback| {
+            check_client(&request(headers), loopback).map_err(|e| e.status)
+        };
+        let json = ("content-type", "application/json; charset=utf-8");
+
+        assert_eq!(status(&[("host", "127.0.0.1:8080"), json], true), Ok(()));
+        assert_eq!(status(&[("host", "[::1]:8080"), json], true), Ok(()));
+        assert_eq!(status(&[("host", "::1"), json], true), Ok(()));
+        assert_eq!(status(&[("host", "127.0.0.2:8080"), json], true), Ok(()));
+        assert_eq!(
+            status(
+                &[
+                    ("host", "localhost:8080"),
+                    json,
+                    ("origin", "https://example.com")
+                ],
+                true
+            ),
+            Err(403)
+        );
+        // a rebound domain
+        assert_eq!(
+            status(&[("host", "evil.example:8080"), json], true),
+            Err(403)
+        );
+        assert_eq!(status(&[json], true), Err(403));
+        assert_eq!(status(&[("host", "192.168.1.2:8080"), json], false), Ok(()));
+        assert_eq!(
+            status(
+                &[("host", "localhost"), ("content-type", "text/plain")],
+                true
+            ),
+            Err(415)
+        );
+    }
+
+    #[test]
+    fn test_check_roles() {
+        assert_eq!(
+            check_roles(&[Message::system("be brief"), Message::user("hi")]),
+            Ok(())
+        );
+        assert_eq!(
+            check_roles(&[Message {
+                role: "tool".to_string(),
+                ..Message::user("{}")
+            }])
+            .map_err(|e| e.status),
+            Err(400)
+        );
+    }
+
+    #[test]
+    fn test_chat_request_parsing() {
+        let chat_request: ChatRequest = parse_body(
+            br#"{"model": "openai:gpt-4o", "messages": [{"role": "user", "content": "hi"}], "stop": "END", "stream": true, "n": 1}"#,
+        )
+        .unwrap();
+
+        assert_eq!(chat_request.model.as_deref(), Some("openai:gpt-4o"));
+        assert_eq!(
+            Message::from(chat_request.messages.into_iter().next().unwrap()),
+            Message::user("hi")
+        );
+        assert!(chat_request.stream);
+        assert_eq!(
+            parse_body::<TemplateRequest>(br#"{"inputs": "x"}"#)
+                .unwrap_err()
+                .status,
+            400
+        );
+    }
+
+    #[test]
+    fn test_completion_format() {
+        let completion = serde_json::to_value(completion(
+            "openai:gpt-4o",
+            "hello".to_string(),
+            Some(TokenUsage {
+                input_tokens: 5,
+                output_tokens: 2,
+            }),
+        ))
+        .unwrap();
+        let chunks =
+            serde_json::to_value(completion_chunks("openai:gpt-4o", "hello".to_string())).unwrap();
+
+        assert_eq!(completion["object"], "chat.completion");
+        assert_eq!(
+            completion["choices"][0]["message"],
+            serde_json::json!({"role": "assistant", "content": "hello"})
+        );
+        assert_eq!(completion["usage"]["total_tokens"], 7);
+        assert_eq!(chunks[0]["choices"][0]["delta"]["content"], "hello");
+        assert_eq!(chunks[1]["choices"][0]["delta"], serde_json::json!({}));
+        assert_eq!(chunks[1]["choices"][0]["finish_reason"], "stop");
+        assert_eq!(chunks[0]["id"], chunks[1]["id"]);
+    }
+
+    #[test]
+    fn test_write_error() {
+        let mut response = Vec::new();
+
+        write_error(&mut response, &HttpError::new(404, "no route")).unwrap();
+
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(response.ends_with("{\"error\":{\"code\":404,\"message\":\"no route\"}}"));
+    }
+}
@@ -0,0 +1,329 @@
+use regex::Regex;
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output};
+
+use crate::config::prompt::Message;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Risk {
+    Low,
+    Medium,
+    High,
+}
+
+/// how much harm running a command could do, and why
+#[derive(Debug, PartialEq)]
+pub struct Assessment {
+    pub risk: Risk,
+    pub reasons: Vec<String>,
+}
+
+impl fmt::Display for Assessment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let risk = match self.risk {
+            Risk::Low => "low",
+            Risk::Medium => "medium",
+            Risk::High => "high",
+        };
+        write!(f, "risk: {}", risk)?;
+        if !self.reasons.is_empty() {
+            write!(f, " ({})", self.reasons.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+const PATTERNS: [(Risk, &str, &str); 14] = [
+    (
+        Risk::High,
+        r"\brm\s+(.*\s)?(-[a-zA-Z]*[rRf][a-zA-Z]*|--recursive|--force)(\s|$)",
+        "deletes recursively or without asking",
+    ),
+    (Risk::High, r"\b(sudo|doas)\b", "runs as root"),
+    (
+        Risk::High,
+        r"\bmkfs|\bdd\s.*\bof=|>\s*/dev/(sd|hd|nvme|disk|mmcblk)",
+        "writes to a disk",
+    ),
+    (
+        Risk::High,
+        r"\|\s*(sudo\s+)?(ba|z|da|fi)?sh\b",
+        "pipes into a shell",
+    ),
+    (Risk::High, r":\(\)\s*\{", "forks endlessly"),
+    (
+        Risk::High,
+        r"\bgit\s+push\s(.*\s)?(--force|-f)\b",
+        "rewrites the remote history",
+    ),
+    (
+        Risk::High,
+        r"\bgit\s+(reset\s(.*\s)?--hard|clean\s+-[a-zA-Z]*f)",
+        "discards uncommitted changes",
+    ),
+    (
+        Risk::High,
+        r"\b(shutdown|reboot|halt|poweroff)\b",
+        "stops the machine",
+    ),
+    (
+        Risk::Medium,
+        r"\brm\s+[^-\s]|\b(rmdir|unlink|shred)\b",
+        "deletes files",
+    ),
+    (
+        Risk::Medium,
+        r"\bfind\s.*\s-(delete|exec\s+rm)\b|\bxargs\s+(.*\s)?rm\b",
+        "deletes the files found",
+    ),
+    (Risk::Medium, r"\bmv\s", "moves files, maybe over others"),
+    (Risk::Medium, r"\btruncate\b", "truncates files"),
+    (
+        Risk::Medium,
+        r"\b(chmod|chown|chgrp)\b",
+        "changes permissions or owners",
+    ),
+    (Risk::Medium, r"\b(kill|pkill|killall)\b", "stops processes"),
+];
+
+/// `$SHELL`, `sh` otherwise
+pub fn user_shell() -> String {
+    std::env::var("SHELL")
+        .ok()
+        .filter(|shell| !shell.is_empty())
+        .unwrap_or_else(|| "sh".to_string())
+}
+
+/// asks for a command only, added right before the input
+pub fn command_request(shell: &str) -> Message {
+    let shell = Path::new(shell)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| shell.to_string());
+    Message::system(&format!(
+        "Answer with a single {} command for {} doing what is asked, without explanation nor \
+        code block, it will be run as is after the user confirms it.",
+        shell,
+        std::env::consts::OS
+    ))
+}
+
+/// The command of the answer, the first code block when there is one, without the prompt sign.
+/// Anything else than a single line is refused rather than run.
+pub fn extract_command(answer: &str) -> Result<String, String> {
+    let command = first_code_block(answer).unwrap_or(answer).trim();
+    let command = command.strip_prefix("$ ").unwrap_or(command).trim();
+    match command.lines().count() {
+        0 => Err("the answer holds no command to run".to_string()),
+        1 => Ok(command.to_string()),
+        _ => Err(format!(
+            "the answer isn't a single command, not running it:\n{}",
+            answer.trim_end()
+        )),
+    }
+}
+
+/// the content of the first fenced code block, the language tag left out
+fn first_code_block(answer: &str) -> Option<&str> {
+    let start = answer.find("```")?;
+    let (_, content) = answer[start + 3..].split_once('\n')?;
+    let end = content.find("```").unwrap_or(content.len());
+    Some(&content[..end])
+}
+
+/// Classify the command against the known dangerous patterns and the files its redirections
+/// would overwrite, relative to `cwd`. The patterns are a safeguard, not a guarantee.
+pub fn assess(command: &str, cwd: &Path) -> Assessment {
+    let mut risk = Risk::Low;
+    let mut reasons = Vec::new();
+    for (pattern_risk, pattern, reason) in PATTERNS {
+        if Regex::new(pattern)
+            .expect("invalid risk pattern")
+            .is_match(command)
+        {
+            risk = risk.max(pattern_risk);
+            reasons.push(reason.to_string());
+        }
+    }
+    for target in overwritten_files(command, cwd) {
+        risk = risk.max(Risk::Medium);
+        reasons.push(format!("overwrites {}", target.display()));
+    }
+    Assessment { risk, reasons }
+}
+
+/// the existing files the `>` redirections of the command would truncate
+fn overwritten_files(command: &str, cwd: &Path) -> Vec<PathBuf> {
+    // `>>` appends and `2>&1` redirects to a descriptor
+    let redirection = Regex::new(r"(?:^|[^>&0-9])(?:[0-9]|&)?>\|?\s*([^\s;&|<>()]+)").unwrap();
+    redirection
+        .captures_iter(command)
+        .filter_map(|captures| {
+            let target = captures[1].trim_matches(['"', '\'']);
+            let path = match target.strip_prefix("~/") {
+                Some(rest) => PathBuf::from(std::env::var("HOME").ok()?).join(rest),
+                None => cwd.join(target),
+            };
+            (!target.starts_with("/dev/") && path.is_file()).then_some(path)
+        })
+        .collect()
+}
+
+/// `y` runs a low or medium risk command, a high risk one needs `yes`. The answer is read from
+/// the terminal when the input was piped, the command isn't run when there is none.
+pub fn confirm(risk: Risk) -> io::Result<bool> {
+    let (question, expected) = if risk == Risk::High {
+        ("Type `yes` to run it: ", "yes")
+    } else {
+        ("Run it? [y/N] ", "y")
+    };
+    eprint!("{}", question);
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    if io::stdin().is_terminal() {
+        io::stdin().read_line(&mut answer)?;
+    } else {
+        match std::fs::File::open("/dev/tty") {
+            Ok(tty) => {
+                io::BufRead::read_line(&mut io::BufReader::new(tty), &mut answer)?;
+            }
+            Err(_) => {
+                eprintln!();
+                return Ok(false);
+            }
+        }
+    }
+    Ok(answer.trim().eq_ignore_ascii_case(expected))
+}
+
+/// run the command in the shell with the terminal as its input and output
+pub fn run(shell: &str, command: &str) -> io::Result<ExitStatus> {
+    Command::new(shell).arg("-c").arg(command).status()
+}
+
+/// run the command in the shell and collect its output to have it explained
+pub fn run_captured(shell: &str, command: &str) -> io::Result<Output> {
+    Command::new(shell).arg("-c").arg(command).output()
+}
+
+/// chars of stdout and of stderr sent to have the output explained
+const OUTPUT_BUDGET: usize = 4000;
+
+/// the follow-up question about what the command printed, each stream cut to the budget
+pub fn explanation_request(command: &str, output: &Output) -> Message {
+    let status = match output.status.code() {
+        Some(code) => format!("exited with code {}", code),
+        None => "was interrupted".to_string(),
+    };
+    Message::user(&format!(
+        "I ran `{}`, it {} and printed:\n\nstdout:\n```\n{}```\n\nstderr:\n```\n{}```\n\n\
+        Explain this output briefly.",
+        command,
+        status,
+        truncate_output(&output.stdout),
+        truncate_output(&output.stderr)
+    ))
+}
+
+fn truncate_output(output: &[u8]) -> String {
+    let output = String::from_utf8_lossy(output);
+    let count = output.chars().count();
+    if count <= OUTPUT_BUDGET {
+        return output.to_string();
+    }
+    let mut truncated: String = output.chars().take(OUTPUT_BUDGET).collect();
+    truncated.push_str(&format!(
+        "\n[{} more chars left out]\n",
+        count - OUTPUT_BUDGET
+    ));
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn risk(command: &str) -> Risk {
+        assess(command, Path::new("/nonexistent")).risk
+    }
+
+    #[test]
+    fn test_assess_patterns() {
+        assert_eq!(risk("find . -type f -size +100M -mtime -7"), Risk::Low);
+        assert_eq!(risk("ls -la > /dev/null 2>&1"), Risk::Low);
+        assert_eq!(risk("rm notes.txt"), Risk::Medium);
+        assert_eq!(risk("find . -name '*.tmp' -delete"), Risk::Medium);
+        assert_eq!(risk("rm -rf build"), Risk::High);
+        assert_eq!(risk("rm build --recursive"), Risk::High);
+        assert_eq!(
+            risk("curl -fsSL https://example.com/install | sh"),
+            Risk::High
+        );
+        assert_eq!(risk("git push -f origin main"), Risk::High);
+        assert_eq!(
+            assess("sudo rm -r /tmp/x", Path::new("/nonexistent")),
+            Assessment {
+                risk: Risk::High,
+                reasons: vec![
+                    "deletes recursively or without asking".to_string(),
+                    "runs as root".to_string()
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn test_assess_redirections() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "keep me").unwrap();
+        let assess = |command| assess(command, dir.path());
+
+        assert_eq!(assess("echo hi >> notes.txt").risk, Risk::Low);
+        assert_eq!(assess("echo hi > new.txt").risk, Risk::Low);
+        assert_eq!(
+            assess("sort words 2>&1 >notes.txt").to_string(),
+            format!(
+                "risk: medium (overwrites {})",
+                dir.path().join("notes.txt").display()
+            )
+        );
+        assert_eq!(assess("echo hi > 'notes.txt'").risk, Risk::Medium);
+    }
+
+    #[test]
+    fn test_extract_command() {
+        assert_eq!(
+            extract_command("```bash\n$ du -sh * | sort -h\n```\n"),
+            Ok("du -sh * | sort -h".to_string())
+        );
+        assert_eq!(
+            extract_command("Here is the command:\n```sh\nfind . -size +100M\n```\nIt lists them."),
+            Ok("find . -size +100M".to_string())
+        );
+        assert_eq!(extract_command("  ls -la\n"), Ok("ls -la".to_string()));
+        assert!(extract_command("cd build\nmake").is_err());
+        assert!(extract_command("Sure:\n```\n```").is_err());
+        assert!(extract_command(" \n").is_err());
+    }
+
+    #[test]
+    fn test_explanation_request_truncates_output() {
+        use std::os::unix::process::ExitStatusExt;
+        let output = Output {
+            status: ExitStatus::from_raw(0),
+            stdout: "a".repeat(OUTPUT_BUDGET + 10).into_bytes(),
+            stderr: Vec::new(),
+        };
+
+        let content = explanation_request("find /", &output).content;
+
+        assert!(content.contains(&format!(
+            "{}\n[10 more chars left out]",
+            "a".repeat(OUTPUT_BUDGET)
+        )));
+        assert!(!content.contains(&"a".repeat(OUTPUT_BUDGET + 1)));
+    }
+}
@@ -1,29 +1,108 @@
 use std::time::Duration;
 
-use super::request_schemas::{AnthropicPrompt, OpenAiPrompt};
-use super::response_schemas::{AnthropicResponse, OllamaResponse, OpenAiResponse};
+use super::cache::{cache_key, ResponseCache};
+use super::ollama;
+use super::request_schemas::{
+    merge_extra_body, request_body, AnthropicPrompt, GeminiPrompt, OllamaPrompt, OpenAiPrompt,
+};
+use super::response_schemas::{
+    Answer, AnthropicResponse, GeminiResponse, OllamaResponse, OpenAiResponse,
+};
 
 use crate::config::{
-    api::{Api, ApiConfig},
-    prompt::{Message, Prompt},
+    api::{get_api_configs, Api, ApiConfig},
+    prompt::{Backend, Message, MessageMetadata, Prompt, TokenUsage},
 };
-use crate::utils::handle_api_response;
+use crate::utils::{handle_api_response, now_seconds, ApiError};
 
 use log::debug;
 
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// limit the number of requests a single answer can take with `auto_continue`
+const MAX_CONTINUATIONS: usize = 5;
+const CONTINUATION_REQUEST: &str = "Your answer was cut, continue exactly where it stopped, \
+    without repeating anything nor adding any comment.";
 
 #[derive(Serialize, Deserialize)]
 #[serde(untagged)]
 enum PromptFormat {
     OpenAi(OpenAiPrompt),
     Anthropic(AnthropicPrompt),
+    Ollama(OllamaPrompt),
+    Gemini(GeminiPrompt),
 }
 
+/// Post the prompt to its api, then to each of its fallback backends in turn
+/// as long as the failures are retryable.
 pub fn post_prompt_and_get_answer(
     api_config: ApiConfig,
     prompt: &Prompt,
-) -> reqwest::Result<Message> {
+    cache: &ResponseCache,
+) -> Result<Message, ApiError> {
+    post_with_fallbacks(api_config, prompt, cache, true)
+}
+
+/// Same as `post_prompt_and_get_answer` for the requests running in parallel threads,
+/// a missing ollama model is an error rather than a question racing for the terminal.
+pub fn post_prompt_in_background(
+    api_config: ApiConfig,
+    prompt: &Prompt,
+    cache: &ResponseCache,
+) -> Result<Message, ApiError> {
+    post_with_fallbacks(api_config, prompt, cache, false)
+}
+
+fn post_with_fallbacks(
+    api_config: ApiConfig,
+    prompt: &Prompt,
+    cache: &ResponseCache,
+    offer_pull: bool,
+) -> Result<Message, ApiError> {
+    let mut result = post_prompt_to_backend(api_config, prompt, cache, offer_pull);
+
+    for backend in prompt.fallback.iter() {
+        match &result {
+            Err(e) if e.is_retryable() => {
+                eprintln!(
+                    "{}, falling back to {}{}.",
+                    e,
+                    backend.api,
+                    backend
+                        .model
+                        .as_ref()
+                        .map(|model| format!(" ({})", model))
+                        .unwrap_or_default()
+                );
+            }
+            _ => break,
+        }
+        // a fallback missing from the api config is skipped, the error stays the last one
+        let Some(fallback_config) = get_api_configs().remove(&backend.api.to_string()) else {
+            eprintln!(
+                "Warning: `{}` isn't configured in the api config file, skipping this fallback.",
+                backend.api
+            );
+            continue;
+        };
+        let fallback_prompt = Prompt {
+            api: backend.api.clone(),
+            model: backend.model.clone(),
+            ..prompt.clone()
+        };
+        result = post_prompt_to_backend(fallback_config, &fallback_prompt, cache, offer_pull);
+    }
+
+    result
+}
+
+fn post_prompt_to_backend(
+    api_config: ApiConfig,
+    prompt: &Prompt,
+    cache: &ResponseCache,
+    offer_pull: bool,
+) -> Result<Message, ApiError> {
     debug!(
         "Trying to reach {:?} with key {:?}",
         api_config.url, api_config.api_key
@@ -39,59 +118,439 @@ pub fn post_prompt_and_get_answer(
     // currently not compatible with streams
     prompt.stream = Some(false);
 
-    let client = reqwest::blocking::Client::builder()
+    let request_body = build_request_body(&prompt);
+    let cache_key = cache_key(
+        &prompt.api,
+        &api_config.url,
+        &prompt.model,
+        prompt.temperature,
+        &request_body,
+    );
+    if let Some(message) = cache.get(&cache_key) {
+        return Ok(message);
+    }
+
+    let client = http_client(&api_config);
+
+    let mut answer = match send_request(&client, &api_config, &prompt, &request_body) {
+        Err(e) if offer_pull && prompt.api == Api::Ollama && ollama::is_missing_model(&e) => {
+            let model = prompt.model.as_deref().unwrap_or_default();
+            if !ollama::offer_to_pull(&api_config, model)? {
+                return Err(e);
+            }
+            send_request(&client, &api_config, &prompt, &request_body)?
+        }
+        result => result?,
+    };
+    let mut content = answer.content;
+    let mut usage = answer.usage;
+    let mut continuations = 0;
+    while answer.truncated {
+        if !prompt.auto_continue {
+            eprintln!(
+                "Warning: the answer was cut because it reached the max tokens, \
+                raise `max_tokens` or enable `auto_continue` to get the rest."
+            );
+            break;
+        }
+        if continuations == MAX_CONTINUATIONS {
+            eprintln!(
+                "Warning: the answer is still cut after {} continuations, giving up.",
+                MAX_CONTINUATIONS
+            );
+            break;
+        }
+        continuations += 1;
+        debug!(
+            "Answer truncated, asking for continuation {}",
+            continuations
+        );
+
+        let mut continuation_prompt = prompt.clone();
+        continuation_prompt
+            .messages
+            .extend(continuation_messages(&prompt.api, &content));
+        answer = send_request(
+            &client,
+            &api_config,
+            &prompt,
+            &build_request_body(&continuation_prompt),
+        )?;
+        stitch(&prompt.api, &mut content, &answer.content);
+        // every request of the answer is billed
+        if let (Some(usage), Some(continuation_usage)) = (usage.as_mut(), answer.usage) {
+            *usage += continuation_usage;
+        }
+    }
+
+    let message = answer_message(&prompt, &content, usage);
+    cache.put(&cache_key, &message);
+    Ok(message)
+}
+
+/// Get `count` different answers, in a single request for the apis supporting `n`
+/// and with parallel requests otherwise.
+pub fn post_prompt_and_get_answers(
+    api_config: ApiConfig,
+    prompt: &Prompt,
+    cache: &ResponseCache,
+    count: usize,
+) -> Result<Vec<Message>, ApiError> {
+    if count <= 1 {
+        return post_prompt_and_get_answer(api_config, prompt, cache).map(|message| vec![message]);
+    }
+    if supports_n(&prompt.api) {
+        return post_prompt_for_choices(api_config, prompt, count);
+    }
+    std::thread::scope(|scope| {
+        let requests: Vec<_> = (0..count)
+            .map(|_| {
+                let api_config = api_config.clone();
+                scope.spawn(move || post_prompt_in_background(api_config, prompt, cache))
+            })
+            .collect();
+        requests
+            .into_iter()
+            .map(|request| request.join().expect("A request thread panicked"))
+            .collect()
+    })
+}
+
+/// the apis accepting `n` to return several choices, groq and cerebras only allow 1
+fn supports_n(api: &Api) -> bool {
+    matches!(api, Api::Openai | Api::AzureOpenai | Api::Mistral)
+}
+
+/// a single request asking for `count` choices, without fallbacks nor continuations
+fn post_prompt_for_choices(
+    api_config: ApiConfig,
+    prompt: &Prompt,
+    count: usize,
+) -> Result<Vec<Message>, ApiError> {
+    let mut prompt = prompt.clone();
+    if prompt.model.is_none() {
+        prompt.model = api_config.default_model.clone()
+    }
+    prompt.stream = Some(false);
+
+    let mut request_body = build_request_body(&prompt);
+    request_body["n"] = count.into();
+    debug!("Asking {:?} for {} choices", api_config.url, count);
+
+    let response = build_request(
+        &http_client(&api_config),
+        &api_config,
+        &prompt,
+        &request_body,
+    )
+    .send()?;
+    let choices = handle_api_response::<OpenAiResponse>(response)?.choices;
+    if choices
+        .iter()
+        .any(|choice| choice.finish_reason.as_deref() == Some("length"))
+    {
+        eprintln!(
+            "Warning: some answers were cut because they reached the max tokens, raise `max_tokens` to get the rest."
+        );
+    }
+    Ok(choices
+        .into_iter()
+        .map(|choice| answer_message(&prompt, &choice.message.content, None))
+        .collect())
+}
+
+/// Open a streamed answer from the openai-like apis, their server-sent events being in the
+/// openai format already. `None` for the other apis.
+pub fn open_openai_stream(
+    api_config: ApiConfig,
+    prompt: &Prompt,
+) -> Result<Option<reqwest::blocking::Response>, ApiError> {
+    if !matches!(
+        prompt.api,
+        Api::Openai | Api::AzureOpenai | Api::Mistral | Api::Groq | Api::Cerebras
+    ) {
+        return Ok(None);
+    }
+    let mut prompt = prompt.clone();
+    if prompt.model.is_none() {
+        prompt.model = api_config.default_model.clone()
+    }
+    prompt.stream = Some(true);
+
+    let request_body = build_request_body(&prompt);
+    let response = build_request(
+        &http_client(&api_config),
+        &api_config,
+        &prompt,
+        &request_body,
+    )
+    .send()?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text()?;
+        return Err(ApiError::Status { status, body });
+    }
+    Ok(Some(response))
+}
+
+fn answer_message(prompt: &Prompt, content: &str, usage: Option<TokenUsage>) -> Message {
+    Message {
+        metadata: Some(MessageMetadata {
+            answered_by: Backend {
+                api: prompt.api.clone(),
+                model: prompt.model.clone(),
+            },
+            created_at: Some(now_seconds()),
+            cached: false,
+            usage,
+        }),
+        ..Message::assistant(content)
+    }
+}
+
+fn http_client(api_config: &ApiConfig) -> reqwest::blocking::Client {
+    reqwest::blocking::Client::builder()
         .timeout(
             api_config
                 .timeout_seconds
                 .map(|t| Duration::from_secs(t.into())),
         )
         .build()
-        .expect("Unable to initialize HTTP client");
+        .expect("Unable to initialize HTTP client")
+}
 
+fn build_request_body(prompt: &Prompt) -> Value {
     let prompt_format = match prompt.api {
-        Api::Ollama | Api::Openai | Api::AzureOpenai | Api::Mistral | Api::Groq | Api::Cerebras => {
+        Api::Openai | Api::AzureOpenai | Api::Mistral | Api::Groq | Api::Cerebras => {
             PromptFormat::OpenAi(OpenAiPrompt::from(prompt.clone()))
         }
+        Api::Ollama => PromptFormat::Ollama(OllamaPrompt::from(prompt.clone())),
         Api::Anthropic => PromptFormat::Anthropic(AnthropicPrompt::from(prompt.clone())),
+        Api::Gemini => PromptFormat::Gemini(GeminiPrompt::from(prompt.clone())),
         Api::AnotherApiForTests => panic!("This api is not made for actual use."),
     };
 
+    let mut request_body = request_body(&prompt_format);
+    if let Some(extra_body) = &prompt.extra_body {
+        merge_extra_body(&mut request_body, extra_body);
+    }
+    request_body
+}
+
+/// Messages asking the model to pick up where the partial answer stopped.
+/// Anthropic and Ollama continue a trailing assistant message as is, the
+/// openai-like apis need to be asked explicitly.
+fn continuation_messages(api: &Api, partial_answer: &str) -> Vec<Message> {
+    match api {
+        // a trailing assistant message can't end with whitespace
+        Api::Anthropic => vec![Message::assistant(partial_answer.trim_end())],
+        Api::Ollama => vec![Message::assistant(partial_answer)],
+        _ => vec![
+            Message::assistant(partial_answer),
+            Message::user(CONTINUATION_REQUEST),
+        ],
+    }
+}
+
+/// Append the continuation to the answer. Anthropic was sent the answer without its trailing
+/// whitespace, when the continuation starts with whitespace it's the one the model put back.
+fn stitch(api: &Api, content: &mut String, continuation: &str) {
+    if *api == Api::Anthropic && continuation.starts_with(char::is_whitespace) {
+        content.truncate(content.trim_end().len());
+    }
+    content.push_str(continuation);
+}
+
+fn send_request(
+    client: &reqwest::blocking::Client,
+    api_config: &ApiConfig,
+    prompt: &Prompt,
+    request_body: &Value,
+) -> Result<Answer, ApiError> {
+    let api = &prompt.api;
+    let response = build_request(client, api_config, prompt, request_body).send()?;
+    let answer = match api {
+        Api::Ollama => handle_api_response::<OllamaResponse>(response)?.into(),
+        Api::Openai | Api::AzureOpenai | Api::Mistral | Api::Groq | Api::Cerebras => {
+            handle_api_response::<OpenAiResponse>(response)?.into()
+        }
+        Api::Anthropic => handle_api_response::<AnthropicResponse>(response)?.into(),
+        Api::Gemini => handle_api_response::<GeminiResponse>(response)?.try_into()?,
+        Api::AnotherApiForTests => unreachable!(),
+    };
+    Ok(answer)
+}
+
+/// the request to the api with its url and authentication
+fn build_request(
+    client: &reqwest::blocking::Client,
+    api_config: &ApiConfig,
+    prompt: &Prompt,
+    request_body: &Value,
+) -> reqwest::blocking::RequestBuilder {
+    let api = &prompt.api;
+    let url = match api {
+        // the model is part of the url
+        Api::Gemini => format!(
+            "{}/{}:generateContent",
+            api_config.url.trim_end_matches('/'),
+            prompt.model.as_ref().expect("model must be specified")
+        ),
+        _ => api_config.url.clone(),
+    };
     let request = client
-        .post(&api_config.url)
+        .post(url)
         .header("Content-Type", "application/json")
-        .json(&prompt_format);
+        .json(request_body);
 
     // https://stackoverflow.com/questions/77862683/rust-reqwest-cant-make-a-request
-    let request = match prompt.api {
+    let request = match api {
         Api::Cerebras => request.header("User-Agent", "CUSTOM_NAME/1.0"),
         _ => request,
     };
 
     // Add auth if necessary
-    let request = match prompt.api {
+    match api {
         Api::Openai | Api::Mistral | Api::Groq | Api::Cerebras => request.header(
             "Authorization",
             &format!("Bearer {}", &api_config.get_api_key()),
         ),
         Api::AzureOpenai => request.header("api-key", &api_config.get_api_key()),
+        Api::Gemini => request.header("x-goog-api-key", &api_config.get_api_key()),
         Api::Anthropic => request
             .header("x-api-key", &api_config.get_api_key())
             .header(
                 "anthropic-version",
-                &api_config.version.expect(
+                api_config.version.as_ref().expect(
                     "version required for Anthropic, please add version key to your api config",
                 ),
             ),
         _ => request,
-    };
+    }
+}
 
-    let response_text: String = match prompt.api {
-        Api::Ollama => handle_api_response::<OllamaResponse>(request.send()?),
-        Api::Openai | Api::AzureOpenai | Api::Mistral | Api::Groq | Api::Cerebras => {
-            handle_api_response::<OpenAiResponse>(request.send()?)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::settings::CacheSettings;
+    use serial_test::serial;
+
+    #[test]
+    fn test_gemini_key_is_sent_in_a_header() {
+        let api_config = ApiConfig {
+            api_key: Some("secret-key".to_string()),
+            url: "https://generativelanguage.googleapis.com/v1beta/models".to_string(),
+            ..ApiConfig::default()
+        };
+        let prompt = Prompt {
+            api: Api::Gemini,
+            model: Some("gemini-2.0-flash".to_string()),
+            ..Prompt::empty()
+        };
+
+        let request = build_request(
+            &reqwest::blocking::Client::new(),
+            &api_config,
+            &prompt,
+            &Value::Null,
+        )
+        .build()
+        .unwrap();
+
+        assert_eq!(
+            request.url().as_str(),
+            "https://generativelanguage.googleapis.com/v1beta/models/gemini-2.0-flash:generateContent"
+        );
+        assert_eq!(request.headers()["x-goog-api-key"], "secret-key");
+    }
+
+    #[test]
+    #[serial]
+    fn test_fallback_missing_from_the_api_config_is_skipped() -> std::io::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let original_value = std::env::var("SMARTCAT_CONFIG_PATH");
+        std::env::set_var("SMARTCAT_CONFIG_PATH", temp_dir.path());
+        let unreachable_url = "http://127.0.0.1:1/api/chat";
+        std::fs::write(
+            temp_dir.path().join(".api_configs.toml"),
+            format!(
+                "[ollama]\nurl = \"{}\"\ndefault_model = \"model\"\n",
+                unreachable_url
+            ),
+        )?;
+        let prompt = Prompt {
+            api: Api::Ollama,
+            model: Some("model".to_string()),
+            messages: vec![Message::user("hello")],
+            fallback: vec![
+                Backend {
+                    api: Api::Openai,
+                    model: None,
+                },
+                Backend {
+                    api: Api::Ollama,
+                    model: None,
+                },
+            ],
+            ..Prompt::empty()
+        };
+        let api_config = ApiConfig {
+            url: unreachable_url.to_string(),
+            ..ApiConfig::default()
+        };
+
+        let result = post_with_fallbacks(
+            api_config,
+            &prompt,
+            &ResponseCache::new(&CacheSettings::default(), true, false),
+            false,
+        );
+
+        match original_value {
+            Ok(val) => std::env::set_var("SMARTCAT_CONFIG_PATH", val),
+            Err(_) => std::env::remove_var("SMARTCAT_CONFIG_PATH"),
         }
-        Api::Anthropic => handle_api_response::<AnthropicResponse>(request.send()?),
-        Api::AnotherApiForTests => unreachable!(),
-    };
-    Ok(Message::assistant(&response_text))
+
+        assert!(matches!(result, Err(e) if e.is_retryable()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_continuation_messages() {
+        let partial_answer = "fn main() {\n";
+
+        let openai_messages = continuation_messages(&Api::Openai, partial_answer);
+        let anthropic_messages = continuation_messages(&Api::Anthropic, partial_answer);
+
+        assert_eq!(
+            openai_messages,
+            vec![
+                Message::assistant("fn main() {\n"),
+                Message::user(CONTINUATION_REQUEST)
+            ]
+        );
+        assert_eq!(anthropic_messages, vec![Message::assistant("fn main() {")]);
+    }
+
+    #[test]
+    fn test_stitch_keeps_the_answer_whitespace() {
+        let stitched = |api, continuation| {
+            let mut content = "fn main() {\n".to_string();
+            stitch(api, &mut content, continuation);
+            content
+        };
+
+        assert_eq!(
+            stitched(&Api::Anthropic, "\n    run();\n}"),
+            "fn main() {\n    run();\n}"
+        );
+        assert_eq!(
+            stitched(&Api::Anthropic, "run();\n}"),
+            "fn main() {\nrun();\n}"
+        );
+        assert_eq!(
+            stitched(&Api::Openai, "    run();\n}"),
+            "fn main() {\n    run();\n}"
+        );
+    }
 }
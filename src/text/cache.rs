@@ -0,0 +1,294 @@
+use log::debug;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::config::{
+    api::Api,
+    prompt::Message,
+    settings::{cache_dir_path, get_settings, CacheSettings},
+};
+use crate::utils::now_seconds;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// neither read nor write the cache
+    Off,
+    /// answer from the cache when possible and store new answers
+    ReadWrite,
+    /// skip the lookup but store the new answer
+    Refresh,
+}
+
+/// On-disk cache of the answers, one file per request in the config dir
+#[derive(Debug, Clone)]
+pub struct ResponseCache {
+    mode: CacheMode,
+    ttl: Duration,
+    dir: PathBuf,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CacheEntry {
+    created_at: u64,
+    message: Message,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub expired: usize,
+    pub size_bytes: u64,
+}
+
+impl ResponseCache {
+    pub fn new(settings: &CacheSettings, no_cache: bool, refresh: bool) -> Self {
+        let mode = if no_cache || !settings.enabled {
+            CacheMode::Off
+        } else if refresh {
+            CacheMode::Refresh
+        } else {
+            CacheMode::ReadWrite
+        };
+        ResponseCache {
+            mode,
+            ttl: Duration::from_secs(settings.ttl_seconds),
+            dir: cache_dir_path(),
+        }
+    }
+
+    /// Cached answer for this key if there is a valid one, reported on stderr
+    /// to make the provenance of the answer clear.
+    pub fn get(&self, key: &str) -> Option<Message> {
+        if self.mode != CacheMode::ReadWrite {
+            return None;
+        }
+        let content = fs::read_to_string(self.entry_path(key)).ok()?;
+        let entry: CacheEntry = serde_json::from_str(&content)
+            .map_err(|e| debug!("ignoring unreadable cache entry {}: {}", key, e))
+            .ok()?;
+        let age = now_seconds().saturating_sub(entry.created_at);
+        if age > self.ttl.as_secs() {
+            return None;
+        }
+        eprintln!(
+            "Answer served from the cache ({} old), use --refresh to request a new one.",
+            format_age(age)
+        );
+        let mut message = entry.message;
+        if let Some(metadata) = message.metadata.as_mut() {
+            metadata.created_at = Some(now_seconds());
+            metadata.cached = true;
+            // nothing was billed for this answer
+            metadata.usage = None;
+        }
+        Some(message)
+    }
+
+    pub fn put(&self, key: &str, message: &Message) {
+        if self.mode == CacheMode::Off {
+            return;
+        }
+        let entry = CacheEntry {
+            created_at: now_seconds(),
+            message: message.clone(),
+        };
+        let written = fs::create_dir_all(&self.dir).and_then(|_| {
+            fs::write(
+                self.entry_path(key),
+                serde_json::to_string(&entry).expect("Failed to serialize cache entry"),
+            )
+        });
+        if let Err(e) = written {
+            eprintln!("Failed to write the answer to the cache: {}", e);
+        }
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+/// Hash of everything that defines the answer of a request
+pub fn cache_key<T: Serialize>(
+    api: &Api,
+    url: &str,
+    model: &Option<String>,
+    temperature: Option<f32>,
+    request_body: &T,
+) -> String {
+    let key_material = serde_json::to_string(&(api, url, model, temperature, request_body))
+        .expect("Failed to serialize the request for the cache key");
+    Sha256::digest(key_material.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub fn clear() -> std::io::Result<usize> {
+    let stats = stats()?;
+    if cache_dir_path().exists() {
+        fs::remove_dir_all(cache_dir_path())?;
+    }
+    Ok(stats.entries)
+}
+
+pub fn stats() -> std::io::Result<CacheStats> {
+    let mut stats = CacheStats {
+        entries: 0,
+        expired: 0,
+        size_bytes: 0,
+    };
+    if !cache_dir_path().exists() {
+        return Ok(stats);
+    }
+    let ttl = get_settings().cache.ttl_seconds;
+    for entry in fs::read_dir(cache_dir_path())? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+        stats.entries += 1;
+        stats.size_bytes += fs::metadata(&path)?.len();
+        let expired = fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<CacheEntry>(&content).ok())
+            .is_none_or(|entry| now_seconds().saturating_sub(entry.created_at) > ttl);
+        if expired {
+            stats.expired += 1;
+        }
+    }
+    Ok(stats)
+}
+
+fn format_age(seconds: u64) -> String {
+    match seconds {
+        s if s < 60 => format!("{}s", s),
+        s if s < 3600 => format!("{}m", s / 60),
+        s if s < 24 * 3600 => format!("{}h", s / 3600),
+        s => format!("{}d", s / (24 * 3600)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::prompt::{Backend, MessageMetadata, TokenUsage};
+
+    fn cache_in(dir: &std::path::Path, mode: CacheMode, ttl_seconds: u64) -> ResponseCache {
+        ResponseCache {
+            mode,
+            ttl: Duration::from_secs(ttl_seconds),
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    #[test]
+    fn test_cache_key_depends_on_every_request_parameter() {
+        let model = Some("gpt-4".to_string());
+        let body = serde_json::json!({"messages": [{"role": "user", "content": "hi"}]});
+        let key = cache_key(&Api::Openai, "url", &model, Some(0.5), &body);
+
+        assert_eq!(
+            key,
+            cache_key(&Api::Openai, "url", &model, Some(0.5), &body)
+        );
+        assert_ne!(
+            key,
+            cache_key(&Api::Openai, "url", &model, Some(0.7), &body)
+        );
+        assert_ne!(
+            key,
+            cache_key(&Api::Mistral, "url", &model, Some(0.5), &body)
+        );
+        assert_ne!(
+            key,
+            cache_key(&Api::Openai, "other_url", &model, Some(0.5), &body)
+        );
+        assert_ne!(key, cache_key(&Api::Openai, "url", &None, Some(0.5), &body));
+        assert_ne!(
+            key,
+            cache_key(
+                &Api::Openai,
+                "url",
+                &model,
+                Some(0.5),
+                &serde_json::json!({})
+            )
+        );
+    }
+
+    #[test]
+    fn test_cache_roundtrip_and_modes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let message = Message::assistant("cached answer");
+
+        cache_in(dir.path(), CacheMode::Off, 60).put("off", &message);
+        assert!(cache_in(dir.path(), CacheMode::ReadWrite, 60)
+            .get("off")
+            .is_none());
+
+        cache_in(dir.path(), CacheMode::Refresh, 60).put("key", &message);
+        assert!(cache_in(dir.path(), CacheMode::Refresh, 60)
+            .get("key")
+            .is_none());
+        assert_eq!(
+            cache_in(dir.path(), CacheMode::ReadWrite, 60).get("key"),
+            Some(message)
+        );
+    }
+
+    #[test]
+    fn test_served_answers_are_marked_as_cached() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = cache_in(dir.path(), CacheMode::ReadWrite, 600);
+        let entry = CacheEntry {
+            created_at: now_seconds() - 120,
+            message: Message {
+                metadata: Some(MessageMetadata {
+                    answered_by: Backend {
+                        api: Api::Openai,
+                        model: None,
+                    },
+                    created_at: Some(now_seconds() - 120),
+                    cached: false,
+                    usage: Some(TokenUsage {
+                        input_tokens: 10,
+                        output_tokens: 20,
+                    }),
+                }),
+                ..Message::assistant("answer")
+            },
+        };
+        fs::write(
+            cache.entry_path("key"),
+            serde_json::to_string(&entry).unwrap(),
+        )
+        .unwrap();
+
+        let metadata = cache.get("key").unwrap().metadata.unwrap();
+
+        assert!(metadata.cached);
+        assert_eq!(metadata.usage, None);
+        assert!(metadata.created_at.unwrap() >= now_seconds() - 5);
+    }
+
+    #[test]
+    fn test_expired_entries_are_not_served() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = cache_in(dir.path(), CacheMode::ReadWrite, 60);
+        let entry = CacheEntry {
+            created_at: now_seconds() - 120,
+            message: Message::assistant("stale answer"),
+        };
+        fs::write(
+            cache.entry_path("key"),
+            serde_json::to_string(&entry).unwrap(),
+        )
+        .unwrap();
+
+        assert!(cache.get("key").is_none());
+    }
+}
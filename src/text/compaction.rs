@@ -0,0 +1,254 @@
+use super::api_call::post_prompt_and_get_answer;
+use super::cache::ResponseCache;
+use crate::config::{
+    api::get_api_config,
+    prompt::{Message, Prompt},
+    settings::{get_settings, CompactionSettings},
+};
+use crate::prompt_customization::CONTEXT_HEADER;
+use crate::utils::ApiError;
+
+/// starts the summary message, an earlier summary is summarized again with the older turns
+const SUMMARY_HEADER: &str = "summary of the earlier conversation:\n\n";
+const SUMMARY_REQUEST: &str = "Summarize the conversation below for your own later use. \
+    Keep the facts, decisions, constraints, open questions and any code, names or values \
+    that may be referred to again, drop the pleasantries. Answer with the summary only.";
+
+#[derive(Debug, PartialEq)]
+pub struct CompactionReport {
+    pub summarized_turns: usize,
+    pub chars_before: usize,
+    pub chars_after: usize,
+}
+
+pub fn conversation_chars(prompt: &Prompt) -> usize {
+    prompt
+        .messages
+        .iter()
+        .map(|message| message.content.len())
+        .sum()
+}
+
+/// Compact the conversation loaded with `-e` if it went over the threshold of the settings,
+/// it's sent as is when the summary can't be made.
+pub fn compact_if_too_long(prompt: &mut Prompt) {
+    let settings = get_settings().compaction;
+    if !needs_compaction(prompt, &settings) {
+        return;
+    }
+    match compact(prompt, settings.keep_last_turns) {
+        Ok(Some(report)) => eprintln!(
+            "Summarized the {} oldest turn(s) of the conversation, {} -> {} chars.",
+            report.summarized_turns, report.chars_before, report.chars_after
+        ),
+        Ok(None) => {}
+        Err(e) => eprintln!("{}, the conversation could not be compacted.", e),
+    }
+}
+
+fn needs_compaction(prompt: &Prompt, settings: &CompactionSettings) -> bool {
+    settings.enabled && conversation_chars(prompt) > settings.threshold_chars
+}
+
+/// Replace the turns before the `keep_last_turns` last ones by a summary written by the
+/// model of the conversation, the leading system messages are kept. `None` if there's
+/// nothing to summarize.
+pub fn compact(
+    prompt: &mut Prompt,
+    keep_last_turns: usize,
+) -> Result<Option<CompactionReport>, ApiError> {
+    let chars_before = conversation_chars(prompt);
+    let (system_messages, older, recent) = split_turns(&prompt.messages, keep_last_turns);
+    let summarized_turns = older
+        .iter()
+        .filter(|message| message.role == "user")
+        .count();
+    if summarized_turns == 0 {
+        return Ok(None);
+    }
+
+    let transcript: String = older
+        .iter()
+        .map(|message| format!("{}: {}\n\n", message.role, message.content))
+        .collect();
+    let summary_prompt = Prompt {
+        messages: vec![Message::system(SUMMARY_REQUEST), Message::user(&transcript)],
+        stop: Vec::new(),
+        extra_body: None,
+        ..prompt.clone()
+    };
+    let cache = ResponseCache::new(&get_settings().cache, false, false);
+    let summary = post_prompt_and_get_answer(
+        get_api_config(&prompt.api.to_string()),
+        &summary_prompt,
+        &cache,
+    )?;
+
+    let mut messages = system_messages;
+    messages.push(Message::system(&format!(
+        "{}{}",
+        SUMMARY_HEADER,
+        summary.content.trim()
+    )));
+    messages.extend(recent);
+    prompt.messages = messages;
+
+    Ok(Some(CompactionReport {
+        summarized_turns,
+        chars_before,
+        chars_after: conversation_chars(prompt),
+    }))
+}
+
+/// Split the messages in the leading system ones, the ones to summarize and the ones of the
+/// `keep_last_turns` last turns, keeping their order. A turn starts with its user message or
+/// the context sent with it, earlier summaries are summarized again.
+fn split_turns(
+    messages: &[Message],
+    keep_last_turns: usize,
+) -> (Vec<Message>, Vec<Message>, Vec<Message>) {
+    let leading = messages
+        .iter()
+        .take_while(|message| {
+            message.role == "system" && !is_summary(message) && !is_context(message)
+        })
+        .count();
+    let system_messages = messages[..leading].to_vec();
+    let exchanges = messages[leading..].to_vec();
+    let turn_starts: Vec<usize> = exchanges
+        .iter()
+        .enumerate()
+        .filter(|(_, message)| message.role == "user")
+        .map(|(index, _)| {
+            let mut start = index;
+            while start > 0 && is_context(&exchanges[start - 1]) {
+                start -= 1;
+            }
+            start
+        })
+        .collect();
+    let kept_turns = keep_last_turns.min(turn_starts.len());
+    let kept_from = match kept_turns {
+        0 => exchanges.len(),
+        _ => turn_starts[turn_starts.len() - kept_turns],
+    };
+    let mut older = exchanges;
+    let recent = older.split_off(kept_from);
+    (system_messages, older, recent)
+}
+
+fn is_summary(message: &Message) -> bool {
+    message.role == "system" && message.content.starts_with(SUMMARY_HEADER)
+}
+
+fn is_context(message: &Message) -> bool {
+    message.role == "system" && message.content.starts_with(CONTEXT_HEADER)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contents(messages: &[Message]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_split_turns_keeps_system_messages_and_last_turns() {
+        let messages = vec![
+            Message::system("instructions"),
+            Message::system(&format!("{}what happened before", SUMMARY_HEADER)),
+            Message::user("question 1"),
+            Message::assistant("answer 1"),
+            Message::system(&format!("{}main.rs", CONTEXT_HEADER)),
+            Message::user("question 2"),
+            Message::assistant("answer 2"),
+            Message::user("question 3"),
+            Message::assistant("answer 3"),
+        ];
+
+        let (system_messages, older, recent) = split_turns(&messages, 2);
+
+        assert_eq!(contents(&system_messages), vec!["instructions"]);
+        assert_eq!(
+            contents(&older),
+            vec![
+                "summary of the earlier conversation:\n\nwhat happened before",
+                "question 1",
+                "answer 1"
+            ]
+        );
+        assert_eq!(
+            contents(&recent),
+            vec![
+                "files content for context:\n\nmain.rs",
+                "question 2",
+                "answer 2",
+                "question 3",
+                "answer 3"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_turns_summarizes_the_context_of_older_turns() {
+        let messages = vec![
+            Message::system("instructions"),
+            Message::system(&format!("{}lib.rs", CONTEXT_HEADER)),
+            Message::user("question 1"),
+            Message::assistant("answer 1"),
+            Message::user("question 2"),
+            Message::assistant("answer 2"),
+        ];
+
+        let (system_messages, older, recent) = split_turns(&messages, 1);
+
+        assert_eq!(contents(&system_messages), vec!["instructions"]);
+        assert_eq!(
+            contents(&older),
+            vec![
+                "files content for context:\n\nlib.rs",
+                "question 1",
+                "answer 1"
+            ]
+        );
+        assert_eq!(contents(&recent), vec!["question 2", "answer 2"]);
+    }
+
+    #[test]
+    fn test_split_turns_edge_cases() {
+        let messages = vec![
+            Message::system("instructions"),
+            Message::user("question 1"),
+            Message::assistant("answer 1"),
+        ];
+
+        let (_, older, recent) = split_turns(&messages, 4);
+        assert!(older.is_empty());
+        assert_eq!(recent.len(), 2);
+
+        let (_, older, recent) = split_turns(&messages, 0);
+        assert_eq!(older.len(), 2);
+        assert!(recent.is_empty());
+    }
+
+    #[test]
+    fn test_needs_compaction() {
+        let prompt = Prompt {
+            messages: vec![Message::user(&"x".repeat(100))],
+            ..Prompt::empty()
+        };
+        let settings = |enabled, threshold_chars| CompactionSettings {
+            enabled,
+            threshold_chars,
+            keep_last_turns: 4,
+        };
+
+        assert!(needs_compaction(&prompt, &settings(true, 99)));
+        assert!(!needs_compaction(&prompt, &settings(true, 100)));
+        assert!(!needs_compaction(&prompt, &settings(false, 99)));
+    }
+}
@@ -0,0 +1,441 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use super::api_call::post_prompt_in_background;
+use super::cache::ResponseCache;
+use super::redaction::Redactions;
+use super::validate_prompt_size;
+use crate::config::{
+    api::{get_api_configs, Api, ApiConfig},
+    prompt::{Backend, Prompt, TokenUsage},
+    settings::{get_settings, ModelAlias},
+};
+
+/// the answer of one backend to the compared prompt
+#[derive(Debug)]
+pub struct Comparison {
+    pub backend: Backend,
+    pub latency: Duration,
+    pub result: Result<String, String>,
+    pub usage: Option<TokenUsage>,
+}
+
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    /// the messages as sent, with the input inserted
+    messages: Vec<ReportMessage<'a>>,
+    results: Vec<ReportResult<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReportMessage<'a> {
+    role: &'a str,
+    content: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+struct ReportResult<'a> {
+    api: &'a Api,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<&'a str>,
+    latency_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    answer: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+/// `api:model`, `api` for its default model, a model alias or a model of the api of the prompt
+pub fn parse_backend(
+    spec: &str,
+    default_api: &Api,
+    aliases: &HashMap<String, ModelAlias>,
+) -> Result<Backend, String> {
+    let spec = spec.trim();
+    if spec.is_empty() {
+        return Err("empty model in the list to compare".to_string());
+    }
+    if let Some(alias) = aliases.get(spec) {
+        return Ok(Backend {
+            api: alias.api.clone().unwrap_or(default_api.clone()),
+            model: Some(alias.model.clone()),
+        });
+    }
+    if let Ok(api) = Api::from_str(spec) {
+        return Ok(Backend { api, model: None });
+    }
+    // ollama models such as `llama3:8b` contain a colon too
+    if let Some((api, model)) = spec.split_once(':') {
+        if let Ok(api) = Api::from_str(api) {
+            return Ok(Backend {
+                api,
+                model: Some(model.to_string()),
+            });
+        }
+    }
+    Ok(Backend {
+        api: default_api.clone(),
+        model: Some(spec.to_string()),
+    })
+}
+
+/// Send the prompt, input already inserted, to every backend concurrently, without
+/// fallbacks nor cache so that the latencies can be compared.
+pub fn compare(prompt: &Prompt, backends: &[Backend], redactions: &Redactions) -> Vec<Comparison> {
+    validate_prompt_size(prompt);
+    compare_with_configs(prompt, backends, &get_api_configs(), redactions)
+}
+
+/// A backend whose api isn't configured or whose request fails unexpectedly
+/// gets an error row, the other ones are still compared.
+fn compare_with_configs(
+    prompt: &Prompt,
+    backends: &[Backend],
+    api_configs: &HashMap<String, ApiConfig>,
+    redactions: &Redactions,
+) -> Vec<Comparison> {
+    let cache = ResponseCache::new(&get_settings().cache, true, false);
+    let restore = get_settings().redaction.restore;
+
+    std::thread::scope(|scope| {
+        let requests: Vec<_> = backends
+            .iter()
+            .map(|backend| {
+                let api_config = api_configs.get(&backend.api.to_string()).cloned();
+                let cache = &cache;
+                scope.spawn(move || {
+                    let Some(api_config) = api_config else {
+                        return failed(
+                            backend,
+                            format!("`{}` isn't configured in the api config file", backend.api),
+                        );
+                    };
+                    let backend = Backend {
+                        api: backend.api.clone(),
+                        model: backend.model.clone().or(api_config.default_model.clone()),
+                    };
+                    let backend_prompt = Prompt {
+                        api: backend.api.clone(),
+                        model: backend.model.clone(),
+                        fallback: Vec::new(),
+                        ..prompt.clone()
+                    };
+                    let start = Instant::now();
+                    let result = post_prompt_in_background(api_config, &backend_prompt, cache);
+                    let latency = start.elapsed();
+                    let usage = result
+                        .as_ref()
+                        .ok()
+                        .and_then(|message| message.metadata.as_ref())
+                        .and_then(|metadata| metadata.usage);
+                    Comparison {
+                        backend,
+                        latency,
+                        result: result
+                            .map(|message| {
+                                if restore {
+                                    redactions.restore(&message.content)
+                                } else {
+                                    message.content
+                                }
+                            })
+                            .map_err(|e| e.to_string()),
+                        usage,
+                    }
+                })
+            })
+            .collect();
+        requests
+            .into_iter()
+            .zip(backends)
+            .map(|(request, backend)| {
+                request.join().unwrap_or_else(|_| {
+                    failed(backend, "the request stopped unexpectedly".to_string())
+                })
+            })
+            .collect()
+    })
+}
+
+fn failed(backend: &Backend, error: String) -> Comparison {
+    Comparison {
+        backend: backend.clone(),
+        latency: Duration::ZERO,
+        result: Err(error),
+        usage: None,
+    }
+}
+
+/// `openai gpt-4o, 1.23s, 120 -> 45 tokens`
+fn title(comparison: &Comparison) -> String {
+    let mut title = comparison.backend.api.to_string();
+    if let Some(model) = &comparison.backend.model {
+        title.push_str(&format!(" {}", model));
+    }
+    title.push_str(&format!(", {:.2}s", comparison.latency.as_secs_f32()));
+    if let Some(usage) = comparison.usage {
+        title.push_str(&format!(
+            ", {} -> {} tokens",
+            usage.input_tokens, usage.output_tokens
+        ));
+    }
+    title
+}
+
+fn body(comparison: &Comparison) -> String {
+    match &comparison.result {
+        Ok(answer) => answer.trim_end().to_string(),
+        Err(e) => format!("Error: {}", e),
+    }
+}
+
+pub fn sequential_view(comparisons: &[Comparison]) -> String {
+    comparisons
+        .iter()
+        .map(|comparison| format!("== {} ==\n{}\n", title(comparison), body(comparison)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// one column per backend within `width` chars, the lines too long are wrapped
+pub fn side_by_side_view(comparisons: &[Comparison], width: usize) -> String {
+    const SEPARATOR: &str = " │ ";
+    let count = comparisons.len().max(1);
+    let column_width =
+        (width.saturating_sub(SEPARATOR.chars().count() * (count - 1)) / count).max(10);
+
+    let columns: Vec<Vec<String>> = comparisons
+        .iter()
+        .map(|comparison| {
+            let mut lines = wrap(&title(comparison), column_width);
+            lines.push("─".repeat(column_width));
+            lines.extend(wrap(&body(comparison), column_width));
+            lines
+        })
+        .collect();
+    let height = columns.iter().map(Vec::len).max().unwrap_or_default();
+
+    let mut view = String::new();
+    for row in 0..height {
+        let cells: Vec<String> = columns
+            .iter()
+            .map(|column| {
+                let cell = column.get(row).map(String::as_str).unwrap_or_default();
+                format!(
+                    "{}{}",
+                    cell,
+                    " ".repeat(column_width - cell.chars().count())
+                )
+            })
+            .collect();
+        view.push_str(cells.join(SEPARATOR).trim_end());
+        view.push('\n');
+    }
+    view
+}
+
+/// wrap every line at `width` chars, on a space when there's one
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        let mut rest: Vec<char> = line.chars().collect();
+        while rest.len() > width {
+            let split = rest[..=width]
+                .iter()
+                .rposition(|c| *c == ' ')
+                .filter(|&position| position > 0);
+            let (head, tail) = match split {
+                Some(position) => (&rest[..position], &rest[position + 1..]),
+                None => (&rest[..width], &rest[width..]),
+            };
+            lines.push(head.iter().collect());
+            rest = tail.to_vec();
+        }
+        lines.push(rest.iter().collect());
+    }
+    lines
+}
+
+pub fn report_json(prompt: &Prompt, comparisons: &[Comparison]) -> String {
+    let report = Report {
+        messages: prompt
+            .messages
+            .iter()
+            .map(|message| ReportMessage {
+                role: &message.role,
+                content: &message.content,
+            })
+            .collect(),
+        results: comparisons
+            .iter()
+            .map(|comparison| ReportResult {
+                api: &comparison.backend.api,
+                model: comparison.backend.model.as_deref(),
+                latency_ms: comparison.latency.as_millis(),
+                input_tokens: comparison.usage.map(|usage| usage.input_tokens),
+                output_tokens: comparison.usage.map(|usage| usage.output_tokens),
+                answer: comparison.result.as_deref().ok(),
+                error: comparison.result.as_ref().err().map(String::as_str),
+            })
+            .collect(),
+    };
+    let mut json = serde_json::to_string_pretty(&report).expect("Failed to serialize the report");
+    json.push('\n');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::prompt::Message;
+
+    fn comparisons() -> Vec<Comparison> {
+        vec![
+            Comparison {
+                backend: Backend {
+                    api: Api::Openai,
+                    model: Some("gpt-4o".to_string()),
+                },
+                latency: Duration::from_millis(1234),
+                result: Ok("parse_config".to_string()),
+                usage: Some(TokenUsage {
+                    input_tokens: 120,
+                    output_tokens: 45,
+                }),
+            },
+            Comparison {
+                backend: Backend {
+                    api: Api::Groq,
+                    model: Some("llama3-70b-8192".to_string()),
+                },
+                latency: Duration::from_millis(400),
+                result: Err("API request failed with status 429".to_string()),
+                usage: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_parse_backend() {
+        let aliases = HashMap::from([(
+            "fast".to_string(),
+            ModelAlias {
+                api: Some(Api::Groq),
+                model: "llama3-70b-8192".to_string(),
+            },
+        )]);
+        let backend = |spec| parse_backend(spec, &Api::Ollama, &aliases).unwrap();
+
+        assert_eq!(
+            backend("openai:gpt-4o"),
+            Backend {
+                api: Api::Openai,
+                model: Some("gpt-4o".to_string())
+            }
+        );
+        assert_eq!(
+            backend("anthropic"),
+            Backend {
+                api: Api::Anthropic,
+                model: None
+            }
+        );
+        assert_eq!(
+            backend("fast"),
+            Backend {
+                api: Api::Groq,
+                model: Some("llama3-70b-8192".to_string())
+            }
+        );
+        assert_eq!(
+            backend("llama3:8b"),
+            Backend {
+                api: Api::Ollama,
+                model: Some("llama3:8b".to_string())
+            }
+        );
+        assert!(parse_backend(" ", &Api::Ollama, &aliases).is_err());
+    }
+
+    #[test]
+    fn test_unconfigured_backend_is_an_error_row() {
+        let prompt = Prompt {
+            messages: vec![Message::user("name this function")],
+            ..Prompt::empty()
+        };
+        let backends = [
+            Backend {
+                api: Api::Openai,
+                model: Some("gpt-4o".to_string()),
+            },
+            Backend {
+                api: Api::Groq,
+                model: None,
+            },
+        ];
+
+        let comparisons =
+            compare_with_configs(&prompt, &backends, &HashMap::new(), &Redactions::default());
+
+        assert_eq!(comparisons.len(), 2);
+        assert_eq!(comparisons[0].backend, backends[0]);
+        assert!(comparisons[0]
+            .result
+            .as_ref()
+            .unwrap_err()
+            .starts_with("`openai` isn't configured"));
+        assert_eq!(comparisons[1].backend, backends[1]);
+        assert!(comparisons[1].result.is_err());
+    }
+
+    #[test]
+    fn test_sequential_view() {
+        assert_eq!(
+            sequential_view(&comparisons()),
+            "== openai gpt-4o, 1.23s, 120 -> 45 tokens ==\nparse_config\n\
+            \n== groq llama3-70b-8192, 0.40s ==\nError: API request failed with status 429\n"
+        );
+    }
+
+    #[test]
+    fn test_side_by_side_view() {
+        let view = side_by_side_view(&comparisons(), 43);
+
+        assert_eq!(
+            view,
+            "openai gpt-4o,       │ groq\n\
+            1.23s, 120 -> 45     │ llama3-70b-8192,\n\
+            tokens               │ 0.40s\n\
+            ──────────────────── │ ────────────────────\n\
+            parse_config         │ Error: API request\n\
+            \x20                    │ failed with status\n\
+            \x20                    │ 429\n"
+        );
+    }
+
+    #[test]
+    fn test_report_json() {
+        let prompt = Prompt {
+            messages: vec![Message::user("name this function")],
+            ..Prompt::empty()
+        };
+        let report: serde_json::Value =
+            serde_json::from_str(&report_json(&prompt, &comparisons())).unwrap();
+
+        assert_eq!(report["messages"][0]["content"], "name this function");
+        assert_eq!(report["results"][0]["latency_ms"], 1234);
+        assert_eq!(report["results"][0]["output_tokens"], 45);
+        assert_eq!(report["results"][0]["answer"], "parse_config");
+        assert_eq!(
+            report["results"][1]["error"],
+            "API request failed with status 429"
+        );
+        assert!(report["results"][1].get("answer").is_none());
+    }
+}
@@ -1,51 +1,171 @@
 mod api_call;
+pub mod cache;
+pub mod compaction;
+pub mod compare;
+pub mod models;
+mod ollama;
+pub mod redaction;
 mod request_schemas;
 mod response_schemas;
 
 use log::debug;
-use std::io::{Result, Write};
-
-use self::api_call::post_prompt_and_get_answer;
-use crate::config::{api::get_api_config, prompt::Prompt, PLACEHOLDER_TOKEN};
-use crate::utils::{is_interactive, read_user_input};
-
-/// insert the input in the prompt, validate the length and make the request
-pub fn process_input_with_request<W: Write>(
+use std::io::{IsTerminal, Result, Write};
+
+use self::api_call::{open_openai_stream, post_prompt_and_get_answer, post_prompt_and_get_answers};
+use self::cache::ResponseCache;
+use self::models::{list_models, warn_if_unknown};
+use self::redaction::{Redactions, Redactor};
+use crate::config::{
+    api::{get_api_config, Api},
+    prompt::{Message, Prompt},
+    settings::get_settings,
+    PLACEHOLDER_TOKEN,
+};
+use crate::utils::{is_interactive, read_user_input, ApiError};
+
+/// insert the input in the prompt, redact it, validate the length and make the request
+pub fn process_input_with_request<W: Write + ?Sized>(
     mut prompt: Prompt,
     mut input: String,
     output: &mut W,
     repeat_input: bool,
+    cache: &ResponseCache,
+    show_redactions: bool,
+    candidates: usize,
 ) -> Result<Prompt> {
-    // insert the input in the messages with placeholders
-    for message in prompt.messages.iter_mut() {
-        message.content = message.content.replace(PLACEHOLDER_TOKEN, &input)
-    }
+    let redactions = insert_input(&mut prompt, &input, show_redactions);
+    let redaction_settings = get_settings().redaction;
+
     // fetch the api config tied to the prompt
     let api_config = get_api_config(&prompt.api.to_string());
 
     validate_prompt_size(&prompt);
-    let response_message = match post_prompt_and_get_answer(api_config, &prompt) {
-        Ok(message) => message,
-        Err(e) => {
-            eprintln!("Failed to make API request: {:?}", e);
+    let model = prompt.model.clone().or(api_config.default_model.clone());
+    let mut response_messages =
+        match post_prompt_and_get_answers(api_config, &prompt, cache, candidates) {
+            Ok(messages) => messages,
+            Err(e) => {
+                eprintln!("{}", e);
+                if let (true, Some(model)) = (e.is_client_error(), model) {
+                    check_model_exists(&prompt.api, &model);
+                }
+                std::process::exit(1);
+            }
+        };
+    debug!("{:?}", &response_messages);
+
+    let answers: Vec<String> = response_messages
+        .iter()
+        .map(|message| {
+            if redaction_settings.restore {
+                redactions.restore(&message.content)
+            } else {
+                message.content.clone()
+            }
+        })
+        .collect();
+
+    // the first candidate is kept in the conversation when they can't be picked from
+    let chosen = if answers.len() == 1 {
+        0
+    } else if is_interactive() && std::io::stdin().is_terminal() {
+        pick_candidate(&answers).unwrap_or_else(|| {
+            eprintln!("No answer picked, the conversation is left as it was.");
             std::process::exit(1);
-        }
+        })
+    } else {
+        output.write_all(candidates_json(&answers).as_bytes())?;
+        prompt.messages.push(response_messages.swap_remove(0));
+        return Ok(prompt);
     };
-    debug!("{}", &response_message.content);
 
-    prompt.messages.push(response_message.clone());
+    prompt.messages.push(response_messages.swap_remove(chosen));
 
     if repeat_input {
         input.push('\n');
         output.write_all(input.as_bytes())?;
     }
 
-    output.write_all(response_message.content.as_bytes())?;
+    output.write_all(answers[chosen].as_bytes())?;
 
     Ok(prompt)
 }
 
-fn validate_prompt_size(prompt: &Prompt) {
+/// insert the input in the messages with placeholders then mask secrets and personal data,
+/// what's saved is what was sent
+pub fn insert_input(prompt: &mut Prompt, input: &str, show_redactions: bool) -> Redactions {
+    for message in prompt.messages.iter_mut() {
+        message.content = message.content.replace(PLACEHOLDER_TOKEN, input)
+    }
+
+    let redaction_settings = get_settings().redaction;
+    let mut redactions = Redactions::after_placeholders_in(
+        prompt
+            .messages
+            .iter()
+            .map(|message| message.content.as_str()),
+    );
+    if redaction_settings.enabled {
+        let redactor = Redactor::new(&redaction_settings);
+        for message in prompt.messages.iter_mut() {
+            message.content = redactor.redact(&message.content, &mut redactions);
+        }
+    }
+    if show_redactions && !redactions.is_empty() {
+        eprint!("{}", redactions);
+    }
+    redactions
+}
+
+/// answer of the api of the prompt, its input already inserted, without the size check
+pub fn request_answer(
+    prompt: &Prompt,
+    cache: &ResponseCache,
+) -> std::result::Result<Message, ApiError> {
+    post_prompt_and_get_answer(get_api_config(&prompt.api.to_string()), prompt, cache)
+}
+
+/// streamed answer in the openai server-sent events format, when the api of the prompt uses it
+pub fn open_stream(
+    prompt: &Prompt,
+) -> std::result::Result<Option<reqwest::blocking::Response>, ApiError> {
+    open_openai_stream(get_api_config(&prompt.api.to_string()), prompt)
+}
+
+/// show the numbered candidates on stderr to keep them out of the output, returns the index picked,
+/// none when the input is closed or left empty
+fn pick_candidate(answers: &[String]) -> Option<usize> {
+    for (index, answer) in answers.iter().enumerate() {
+        eprintln!("[{}]\n{}\n", index + 1, answer.trim_end());
+    }
+    loop {
+        eprint!("Pick the answer to keep [1-{}]: ", answers.len());
+        let mut choice = String::new();
+        match std::io::stdin().read_line(&mut choice) {
+            Ok(0) | Err(_) => return None,
+            Ok(_) if choice.trim().is_empty() => return None,
+            Ok(_) => match choice.trim().parse::<usize>() {
+                Ok(number) if (1..=answers.len()).contains(&number) => return Some(number - 1),
+                _ => eprintln!("Please enter a number between 1 and {}.", answers.len()),
+            },
+        }
+    }
+}
+
+fn candidates_json(answers: &[String]) -> String {
+    let mut json = serde_json::to_string_pretty(answers).expect("Failed to serialize the answers");
+    json.push('\n');
+    json
+}
+
+/// the request was refused, a stale or mistyped model name is a likely culprit
+fn check_model_exists(api: &Api, model: &str) {
+    if let Ok(Some(available_models)) = list_models(api, &get_api_config(&api.to_string())) {
+        warn_if_unknown(api, model, &available_models, "");
+    }
+}
+
+pub fn validate_prompt_size(prompt: &Prompt) {
     let char_limit = prompt.char_limit.unwrap_or_default();
     let number_of_chars: u32 = prompt
         .messages
@@ -109,6 +229,16 @@ mod tests {
         };
     }
 
+    #[test]
+    fn test_candidates_json() {
+        let answers = vec!["parse_config".to_string(), "load \"config\"".to_string()];
+
+        assert_eq!(
+            serde_json::from_str::<Vec<String>>(&candidates_json(&answers)).unwrap(),
+            answers
+        );
+    }
+
     test_process_input!(
         test_with_prefix_and_suffix,
         "Prefix: ",
@@ -0,0 +1,207 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use super::ollama;
+use crate::config::{
+    api::{Api, ApiConfig},
+    prompt::Prompt,
+};
+use crate::utils::{handle_api_response, sibling_url, ApiError};
+
+/// max number of models asked per request, the apis paginate otherwise
+const PAGE_SIZE: &str = "1000";
+
+/// openai, mistral, groq, cerebras and anthropic
+#[derive(Debug, Deserialize)]
+struct ModelList {
+    data: Vec<ModelId>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelId {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GeminiModelList {
+    #[serde(default)]
+    models: Vec<GeminiModel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GeminiModel {
+    /// `models/<id>`
+    name: String,
+}
+
+/// Ids of the models the api offers, `None` for the apis which can't list them.
+pub fn list_models(api: &Api, api_config: &ApiConfig) -> Result<Option<Vec<String>>, ApiError> {
+    let client = reqwest::blocking::Client::new();
+    let models = match api {
+        Api::Openai | Api::Mistral | Api::Groq | Api::Cerebras => {
+            let url = sibling_url(&api_config.url, "chat/completions", "models");
+            let request = client.get(url).header(
+                "Authorization",
+                format!("Bearer {}", api_config.get_api_key()),
+            );
+            // https://stackoverflow.com/questions/77862683/rust-reqwest-cant-make-a-request
+            let request = match api {
+                Api::Cerebras => request.header("User-Agent", "CUSTOM_NAME/1.0"),
+                _ => request,
+            };
+            ids(handle_api_response::<ModelList>(request.send()?)?)
+        }
+        Api::Anthropic => {
+            let request = client
+                .get(sibling_url(&api_config.url, "messages", "models"))
+                .query(&[("limit", PAGE_SIZE)])
+                .header("x-api-key", api_config.get_api_key())
+                .header(
+                    "anthropic-version",
+                    api_config.version.as_deref().unwrap_or_default(),
+                );
+            ids(handle_api_response::<ModelList>(request.send()?)?)
+        }
+        Api::Gemini => {
+            let request = client
+                .get(api_config.url.trim_end_matches('/'))
+                .query(&[("pageSize", PAGE_SIZE)])
+                .header("x-goog-api-key", api_config.get_api_key());
+            handle_api_response::<GeminiModelList>(request.send()?)?
+                .models
+                .into_iter()
+                .map(|model| {
+                    model
+                        .name
+                        .strip_prefix("models/")
+                        .map(str::to_string)
+                        .unwrap_or(model.name)
+                })
+                .collect()
+        }
+        Api::Ollama => ollama::list_models(api_config)?
+            .into_iter()
+            .map(|model| model.name)
+            .collect(),
+        // the model is set by the deployment in the url
+        Api::AzureOpenai | Api::AnotherApiForTests => return Ok(None),
+    };
+    Ok(Some(models))
+}
+
+fn ids(model_list: ModelList) -> Vec<String> {
+    model_list.data.into_iter().map(|model| model.id).collect()
+}
+
+/// whether the model is among the listed ones, ollama's `:latest` tag being optional
+pub fn is_known(model: &str, models: &[String]) -> bool {
+    models
+        .iter()
+        .any(|known| known == model || known.strip_suffix(":latest") == Some(model))
+}
+
+/// the listed model the most likely meant: one starting with the name, e.g. a dated
+/// version, or else the closest one if it's close enough
+pub fn did_you_mean<'a>(model: &str, models: &'a [String]) -> Option<&'a str> {
+    let extensions = models
+        .iter()
+        .filter(|known| known.starts_with(model))
+        .min_by_key(|known| known.len());
+    if let Some(extension) = extensions {
+        return Some(extension);
+    }
+    models
+        .iter()
+        .map(|known| (edit_distance(model, known), known))
+        .filter(|(distance, _)| *distance <= model.chars().count() / 3)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, known)| known.as_str())
+}
+
+/// Warn about a model the api doesn't list, with a suggestion if one comes close.
+/// `used_by` tells where the model comes from, e.g. " in prompt `default`"
+pub fn warn_if_unknown(api: &Api, model: &str, models: &[String], used_by: &str) {
+    if is_known(model, models) {
+        return;
+    }
+    let suggestion = did_you_mean(model, models)
+        .map(|known| format!(", did you mean `{}`?", known))
+        .unwrap_or_else(|| ", run `sc models` to see the available ones.".to_string());
+    eprintln!(
+        "Warning: model `{}`{} is not listed by {}{}",
+        model, used_by, api, suggestion
+    );
+}
+
+/// Check the default model of the api config and the models of the prompts using the api.
+pub fn check_configured_models(
+    api: &Api,
+    api_config: &ApiConfig,
+    prompts: &HashMap<String, Prompt>,
+    models: &[String],
+) {
+    if let Some(model) = &api_config.default_model {
+        warn_if_unknown(api, model, models, " set as `default_model`");
+    }
+    let mut prompts: Vec<_> = prompts
+        .iter()
+        .filter(|(_, prompt)| prompt.api == *api)
+        .collect();
+    prompts.sort_by_key(|(name, _)| name.as_str());
+    for (name, prompt) in prompts {
+        if let Some(model) = &prompt.model {
+            warn_if_unknown(api, model, models, &format!(" in prompt `{}`", name));
+        }
+    }
+}
+
+/// levenshtein distance
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn models() -> Vec<String> {
+        [
+            "claude-3-opus-20240229",
+            "claude-3-5-sonnet-20241022",
+            "llama3:latest",
+        ]
+        .iter()
+        .map(|model| model.to_string())
+        .collect()
+    }
+
+    #[test]
+    fn test_is_known() {
+        assert!(is_known("claude-3-opus-20240229", &models()));
+        assert!(is_known("llama3", &models()));
+        assert!(!is_known("claude-3-opus", &models()));
+    }
+
+    #[test]
+    fn test_did_you_mean() {
+        assert_eq!(
+            did_you_mean("claude-3-opus", &models()),
+            Some("claude-3-opus-20240229")
+        );
+        assert_eq!(
+            did_you_mean("claude-3-5-sonet-20241022", &models()),
+            Some("claude-3-5-sonnet-20241022")
+        );
+        assert_eq!(did_you_mean("gpt-4o", &models()), None);
+    }
+}
@@ -0,0 +1,153 @@
+use serde::Deserialize;
+use std::io::{BufRead, BufReader, Write};
+
+use crate::config::api::ApiConfig;
+use crate::utils::{handle_api_response, is_interactive, read_user_input, ApiError};
+
+const PROGRESS_BAR_WIDTH: usize = 30;
+
+#[derive(Debug, Deserialize)]
+struct TagsResponse {
+    models: Vec<OllamaModel>,
+}
+
+/// a model available locally, https://github.com/ollama/ollama/blob/main/docs/api.md#list-local-models
+#[derive(Debug, Deserialize)]
+pub struct OllamaModel {
+    pub name: String,
+}
+
+/// one line of the streamed answer of `/api/pull`
+#[derive(Debug, Deserialize)]
+struct PullProgress {
+    #[serde(default)]
+    status: String,
+    total: Option<u64>,
+    completed: Option<u64>,
+    error: Option<String>,
+}
+
+/// the chat url of the config is `<base>/api/chat`, the other endpoints live next to it
+fn base_url(api_config: &ApiConfig) -> &str {
+    let url = api_config.url.trim_end_matches('/');
+    url.strip_suffix("/api/chat").unwrap_or(url)
+}
+
+pub fn list_models(api_config: &ApiConfig) -> Result<Vec<OllamaModel>, ApiError> {
+    let response = reqwest::blocking::get(format!("{}/api/tags", base_url(api_config)))?;
+    Ok(handle_api_response::<TagsResponse>(response)?.models)
+}
+
+/// ollama answers 404 when the requested model hasn't been pulled
+pub fn is_missing_model(error: &ApiError) -> bool {
+    matches!(
+        error,
+        ApiError::Status { status, body }
+            if status.as_u16() == 404 && body.contains("not found")
+    )
+}
+
+/// Ask the user whether to pull the missing model, returns whether it was pulled.
+pub fn offer_to_pull(api_config: &ApiConfig, model: &str) -> Result<bool, ApiError> {
+    if !is_interactive() {
+        return Ok(false);
+    }
+    eprintln!(
+        "Model {} is not available in ollama, do you want to pull it?\n[Y/n]",
+        model
+    );
+    if read_user_input() != "Y" {
+        return Ok(false);
+    }
+    pull_model(api_config, model)?;
+    Ok(true)
+}
+
+fn pull_model(api_config: &ApiConfig, model: &str) -> Result<(), ApiError> {
+    // pulls take a while, no timeout
+    let client = reqwest::blocking::Client::builder()
+        .timeout(None)
+        .build()
+        .expect("Unable to initialize HTTP client");
+    let response = client
+        .post(format!("{}/api/pull", base_url(api_config)))
+        .json(&serde_json::json!({ "model": model, "stream": true }))
+        .send()?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text()?;
+        return Err(ApiError::Status { status, body });
+    }
+
+    let mut stderr = std::io::stderr();
+    for line in BufReader::new(response).lines() {
+        let line = line.map_err(|e| ApiError::Stream(e.to_string()))?;
+        let Ok(progress) = serde_json::from_str::<PullProgress>(&line) else {
+            continue;
+        };
+        if let Some(error) = progress.error {
+            eprintln!();
+            return Err(ApiError::Stream(error));
+        }
+        let _ = write!(stderr, "\r\x1b[2K{}", render_progress(&progress));
+        let _ = stderr.flush();
+    }
+    eprintln!();
+    Ok(())
+}
+
+fn render_progress(progress: &PullProgress) -> String {
+    match (progress.total, progress.completed) {
+        (Some(total), Some(completed)) if total > 0 => {
+            let ratio = completed.min(total) as f64 / total as f64;
+            let filled = (ratio * PROGRESS_BAR_WIDTH as f64) as usize;
+            format!(
+                "{} [{}{}] {:>3}% {}/{} MB",
+                progress.status,
+                "#".repeat(filled),
+                " ".repeat(PROGRESS_BAR_WIDTH - filled),
+                (ratio * 100.0) as u32,
+                completed / 1_000_000,
+                total / 1_000_000
+            )
+        }
+        _ => progress.status.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base_url() {
+        let api_config = ApiConfig {
+            url: "http://localhost:11434/api/chat/".to_string(),
+            ..ApiConfig::default()
+        };
+
+        assert_eq!(base_url(&api_config), "http://localhost:11434");
+    }
+
+    #[test]
+    fn test_render_progress() {
+        let downloading = PullProgress {
+            status: "pulling 6a0746a1ec1a".to_string(),
+            total: Some(4_000_000_000),
+            completed: Some(1_000_000_000),
+            error: None,
+        };
+        let verifying = PullProgress {
+            status: "verifying sha256 digest".to_string(),
+            total: None,
+            completed: None,
+            error: None,
+        };
+
+        assert_eq!(
+            render_progress(&downloading),
+            "pulling 6a0746a1ec1a [#######                       ]  25% 1000/4000 MB"
+        );
+        assert_eq!(render_progress(&verifying), "verifying sha256 digest");
+    }
+}
@@ -1,7 +1,15 @@
-use crate::config::prompt::{Message, Prompt};
+use crate::config::{
+    api::Api,
+    prompt::{Message, Prompt},
+};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::fmt::Debug;
 
+const ANTHROPIC_DEFAULT_MAX_TOKENS: u32 = 4096;
+/// shorter prompts can't be cached, the minimum is 1024 tokens for most models (~4 chars per token)
+const ANTHROPIC_CACHE_MIN_CHARS: usize = 4096;
+
 #[derive(Debug, Deserialize, Serialize)]
 pub(super) struct OpenAiPrompt {
     pub model: String,
@@ -10,58 +18,527 @@ pub(super) struct OpenAiPrompt {
     pub temperature: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    /// mistral's name for the seed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub random_seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub(super) struct AnthropicPrompt {
     pub model: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub system: Vec<AnthropicTextBlock>,
     pub messages: Vec<Message>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f32>,
-    pub max_tokens: i32,
+    pub max_tokens: u32,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+}
+
+/// https://docs.anthropic.com/en/docs/build-with-claude/prompt-caching
+#[derive(Debug, Deserialize, Serialize)]
+pub(super) struct AnthropicTextBlock {
+    #[serde(rename = "type")]
+    pub _type: String,
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<AnthropicCacheControl>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(super) struct AnthropicCacheControl {
+    #[serde(rename = "type")]
+    pub _type: String,
+}
+
+/// https://ai.google.dev/api/generate-content
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct GeminiPrompt {
+    pub contents: Vec<GeminiContent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_instruction: Option<GeminiContent>,
+    #[serde(default)]
+    pub generation_config: GeminiGenerationConfig,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(super) struct GeminiContent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    pub parts: Vec<GeminiPart>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(super) struct GeminiPart {
+    pub text: String,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct GeminiGenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+}
+
+/// https://github.com/ollama/ollama/blob/main/docs/api.md#generate-a-chat-completion
+/// sampling parameters go in the `options`
+#[derive(Debug, Deserialize, Serialize)]
+pub(super) struct OllamaPrompt {
+    pub model: String,
+    pub messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub options: Map<String, Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_alive: Option<String>,
 }
 
 impl From<Prompt> for OpenAiPrompt {
     fn from(prompt: Prompt) -> OpenAiPrompt {
+        let (seed, random_seed) = match prompt.api {
+            Api::Mistral => (None, prompt.seed),
+            _ => (prompt.seed, None),
+        };
         OpenAiPrompt {
             model: prompt
                 .model
                 .expect("model must be specified either in the api config or in the prompt config"),
-            messages: prompt.messages,
+            messages: prompt
+                .messages
+                .into_iter()
+                .map(Message::without_metadata)
+                .collect(),
             temperature: prompt.temperature,
             stream: prompt.stream,
+            max_tokens: prompt.max_tokens,
+            top_p: prompt.top_p,
+            stop: prompt.stop,
+            seed,
+            random_seed,
+            presence_penalty: prompt.presence_penalty,
         }
     }
 }
 
 impl From<Prompt> for AnthropicPrompt {
     fn from(prompt: Prompt) -> Self {
-        let merged_messages =
-            prompt
-                .messages
-                .into_iter()
-                .fold(Vec::new(), |mut acc: Vec<Message>, mut message| {
-                    if message.role == "system" {
-                        message.role = "user".to_string();
-                    }
-                    match acc.last_mut() {
-                        Some(last_message) if last_message.role == message.role => {
-                            last_message.content.push_str("\n\n");
-                            last_message.content.push_str(&message.content);
-                        }
-                        _ => acc.push(message),
-                    }
-                    acc
+        let (system_messages, messages): (Vec<Message>, Vec<Message>) = prompt
+            .messages
+            .into_iter()
+            .map(Message::without_metadata)
+            .partition(|message| message.role == "system");
+        let merged_messages = merge_consecutive_roles(messages);
+
+        let system_chars: usize = system_messages.iter().map(|m| m.content.len()).sum();
+        let mut system: Vec<AnthropicTextBlock> = system_messages
+            .into_iter()
+            .map(|message| AnthropicTextBlock {
+                _type: "text".to_string(),
+                text: message.content,
+                cache_control: None,
+            })
+            .collect();
+        // the breakpoint caches everything before it, the context included
+        if system_chars >= ANTHROPIC_CACHE_MIN_CHARS {
+            if let Some(last_block) = system.last_mut() {
+                last_block.cache_control = Some(AnthropicCacheControl {
+                    _type: "ephemeral".to_string(),
                 });
+            }
+        }
 
         AnthropicPrompt {
             model: prompt.model.expect("model must be specified"),
+            system,
             messages: merged_messages,
             temperature: prompt.temperature,
             stream: prompt.stream,
-            max_tokens: 4096,
+            max_tokens: prompt.max_tokens.unwrap_or(ANTHROPIC_DEFAULT_MAX_TOKENS),
+            top_p: prompt.top_p,
+            top_k: prompt.top_k,
+            stop_sequences: prompt.stop,
+        }
+    }
+}
+
+impl From<Prompt> for GeminiPrompt {
+    fn from(prompt: Prompt) -> Self {
+        let (system_messages, messages): (Vec<Message>, Vec<Message>) = prompt
+            .messages
+            .into_iter()
+            .partition(|message| message.role == "system");
+
+        let text_parts = |message: Message| {
+            vec![GeminiPart {
+                text: message.content,
+            }]
+        };
+        let contents = merge_consecutive_roles(messages)
+            .into_iter()
+            .map(|message| GeminiContent {
+                role: Some(match message.role.as_str() {
+                    "assistant" => "model".to_string(),
+                    role => role.to_string(),
+                }),
+                parts: text_parts(message),
+            })
+            .collect();
+        let system_instruction = (!system_messages.is_empty()).then(|| GeminiContent {
+            role: None,
+            parts: system_messages.into_iter().flat_map(text_parts).collect(),
+        });
+
+        GeminiPrompt {
+            contents,
+            system_instruction,
+            generation_config: GeminiGenerationConfig {
+                temperature: prompt.temperature,
+                top_p: prompt.top_p,
+                top_k: prompt.top_k,
+                max_output_tokens: prompt.max_tokens,
+                stop_sequences: prompt.stop,
+                seed: prompt.seed,
+                presence_penalty: prompt.presence_penalty,
+            },
         }
     }
 }
+
+impl From<Prompt> for OllamaPrompt {
+    fn from(prompt: Prompt) -> Self {
+        let mut options = prompt.options.unwrap_or_default();
+        let parameters = [
+            ("temperature", prompt.temperature.map(f32_value)),
+            ("top_p", prompt.top_p.map(f32_value)),
+            ("top_k", prompt.top_k.map(Value::from)),
+            ("seed", prompt.seed.map(Value::from)),
+            ("num_predict", prompt.max_tokens.map(Value::from)),
+            ("presence_penalty", prompt.presence_penalty.map(f32_value)),
+            (
+                "stop",
+                (!prompt.stop.is_empty()).then(|| Value::from(prompt.stop)),
+            ),
+        ];
+        for (name, value) in parameters {
+            if let Some(value) = value {
+                options.insert(name.to_string(), value);
+            }
+        }
+
+        OllamaPrompt {
+            model: prompt
+                .model
+                .expect("model must be specified either in the api config or in the prompt config"),
+            messages: prompt
+                .messages
+                .into_iter()
+                .map(Message::without_metadata)
+                .collect(),
+            stream: prompt.stream,
+            options,
+            keep_alive: prompt.keep_alive,
+        }
+    }
+}
+
+/// merge the consecutive messages of a same role, for the apis expecting them to alternate
+fn merge_consecutive_roles(messages: Vec<Message>) -> Vec<Message> {
+    messages
+        .into_iter()
+        .fold(Vec::new(), |mut acc: Vec<Message>, message| {
+            match acc.last_mut() {
+                Some(last_message) if last_message.role == message.role => {
+                    last_message.content.push_str("\n\n");
+                    last_message.content.push_str(&message.content);
+                }
+                _ => acc.push(message),
+            }
+            acc
+        })
+}
+
+/// 0.7 rather than the 0.699999988079071 a direct conversion to f64 gives
+fn f32_value(value: f32) -> Value {
+    Value::from(value.to_string().parse::<f64>().unwrap_or(value.into()))
+}
+
+/// serialized through a string so that f32 fields are sent as 0.7 and not 0.699999988079071
+pub(super) fn request_body<T: Serialize>(prompt: &T) -> Value {
+    let body = serde_json::to_string(prompt).expect("Failed to serialize the request body");
+    serde_json::from_str(&body).expect("Failed to serialize the request body")
+}
+
+/// Recursively merge the `extra_body` of a prompt into the request body, its values win
+pub(super) fn merge_extra_body(body: &mut Value, extra_body: &Map<String, Value>) {
+    let Value::Object(body) = body else {
+        return;
+    };
+    for (key, extra_value) in extra_body {
+        match (body.get_mut(key), extra_value) {
+            (Some(value @ Value::Object(_)), Value::Object(extra_object)) => {
+                merge_extra_body(value, extra_object)
+            }
+            _ => {
+                body.insert(key.clone(), extra_value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        api::Api,
+        prompt::{Backend, MessageMetadata},
+    };
+
+    #[test]
+    fn test_metadata_is_not_sent() {
+        let answer = Message {
+            metadata: Some(MessageMetadata {
+                answered_by: Backend {
+                    api: Api::Groq,
+                    model: None,
+                },
+                created_at: None,
+                cached: false,
+                usage: None,
+            }),
+            ..Message::assistant("answer")
+        };
+        let prompt = Prompt {
+            model: Some("model".to_string()),
+            messages: vec![Message::user("question"), answer, Message::user("more")],
+            ..Prompt::empty()
+        };
+
+        let openai_body = request_body(&OpenAiPrompt::from(prompt.clone()));
+        let anthropic_body = request_body(&AnthropicPrompt::from(prompt));
+
+        assert!(!openai_body.to_string().contains("metadata"));
+        assert!(!anthropic_body.to_string().contains("metadata"));
+    }
+
+    fn prompt_with_parameters(api: Api) -> Prompt {
+        Prompt {
+            api,
+            model: Some("model".to_string()),
+            messages: vec![Message::user("question")],
+            temperature: Some(0.7),
+            max_tokens: Some(100),
+            top_p: Some(0.9),
+            top_k: Some(40),
+            stop: vec!["END".to_string()],
+            seed: Some(7),
+            presence_penalty: Some(0.5),
+            ..Prompt::empty()
+        }
+    }
+
+    #[test]
+    fn test_openai_parameters() {
+        let body = request_body(&OpenAiPrompt::from(prompt_with_parameters(Api::Openai)));
+
+        assert_eq!(body["max_tokens"], 100);
+        assert_eq!(body["top_p"], 0.9);
+        assert_eq!(body["stop"], serde_json::json!(["END"]));
+        assert_eq!(body["seed"], 7);
+        assert_eq!(body["presence_penalty"], 0.5);
+        assert!(body.get("random_seed").is_none());
+        assert!(body.get("top_k").is_none());
+    }
+
+    #[test]
+    fn test_mistral_seed_is_random_seed() {
+        let body = request_body(&OpenAiPrompt::from(prompt_with_parameters(Api::Mistral)));
+
+        assert_eq!(body["random_seed"], 7);
+        assert!(body.get("seed").is_none());
+    }
+
+    #[test]
+    fn test_anthropic_parameters() {
+        let body = request_body(&AnthropicPrompt::from(prompt_with_parameters(
+            Api::Anthropic,
+        )));
+        let default_body = request_body(&AnthropicPrompt::from(Prompt {
+            model: Some("model".to_string()),
+            ..Prompt::empty()
+        }));
+
+        assert_eq!(body["max_tokens"], 100);
+        assert_eq!(body["top_k"], 40);
+        assert_eq!(body["stop_sequences"], serde_json::json!(["END"]));
+        assert!(body.get("seed").is_none());
+        assert_eq!(default_body["max_tokens"], ANTHROPIC_DEFAULT_MAX_TOKENS);
+    }
+
+    #[test]
+    fn test_ollama_parameters_go_in_options() {
+        let mut prompt = prompt_with_parameters(Api::Ollama);
+        prompt.options = Some(Map::from_iter([
+            ("num_ctx".to_string(), Value::from(8192)),
+            ("top_k".to_string(), Value::from(10)),
+        ]));
+        prompt.keep_alive = Some("30m".to_string());
+
+        let body = request_body(&OllamaPrompt::from(prompt));
+
+        assert_eq!(
+            body["options"],
+            serde_json::json!({
+                "num_ctx": 8192,
+                "temperature": 0.7,
+                "top_p": 0.9,
+                "top_k": 40,
+                "seed": 7,
+                "num_predict": 100,
+                "presence_penalty": 0.5,
+                "stop": ["END"],
+            })
+        );
+        assert_eq!(body["keep_alive"], "30m");
+        assert!(body.get("max_tokens").is_none());
+    }
+
+    #[test]
+    fn test_merge_extra_body() {
+        let mut body = serde_json::json!({
+            "model": "model",
+            "options": { "temperature": 0.7, "num_ctx": 2048 },
+        });
+        let extra_body = serde_json::json!({
+            "options": { "num_ctx": 8192 },
+            "keep_alive": "10m",
+        });
+
+        merge_extra_body(&mut body, extra_body.as_object().unwrap());
+
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "model": "model",
+                "options": { "temperature": 0.7, "num_ctx": 8192 },
+                "keep_alive": "10m",
+            })
+        );
+    }
+
+    #[test]
+    fn test_anthropic_system_messages_use_the_system_field() {
+        let prompt = Prompt {
+            model: Some("model".to_string()),
+            messages: vec![
+                Message::system("be brief"),
+                Message::user("question"),
+                Message::user("more"),
+                Message::system("files content for context"),
+            ],
+            ..Prompt::empty()
+        };
+
+        let body = request_body(&AnthropicPrompt::from(prompt));
+
+        assert_eq!(
+            body["system"],
+            serde_json::json!([
+                {"type": "text", "text": "be brief"},
+                {"type": "text", "text": "files content for context"},
+            ])
+        );
+        assert_eq!(
+            body["messages"],
+            serde_json::json!([{"role": "user", "content": "question\n\nmore"}])
+        );
+    }
+
+    #[test]
+    fn test_anthropic_large_system_prompt_is_cached() {
+        let context = "x".repeat(ANTHROPIC_CACHE_MIN_CHARS);
+        let prompt = Prompt {
+            model: Some("model".to_string()),
+            messages: vec![
+                Message::system("be brief"),
+                Message::system(&context),
+                Message::user("question"),
+            ],
+            ..Prompt::empty()
+        };
+
+        let body = request_body(&AnthropicPrompt::from(prompt));
+
+        assert!(body["system"][0].get("cache_control").is_none());
+        assert_eq!(
+            body["system"][1]["cache_control"],
+            serde_json::json!({"type": "ephemeral"})
+        );
+    }
+
+    #[test]
+    fn test_gemini_prompt() {
+        let prompt = Prompt {
+            messages: vec![
+                Message::system("be brief"),
+                Message::user("question"),
+                Message::assistant("answer"),
+                Message::user("more"),
+            ],
+            ..prompt_with_parameters(Api::Gemini)
+        };
+
+        let body = request_body(&GeminiPrompt::from(prompt));
+
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "contents": [
+                    {"role": "user", "parts": [{"text": "question"}]},
+                    {"role": "model", "parts": [{"text": "answer"}]},
+                    {"role": "user", "parts": [{"text": "more"}]},
+                ],
+                "systemInstruction": {"parts": [{"text": "be brief"}]},
+                "generationConfig": {
+                    "temperature": 0.7,
+                    "topP": 0.9,
+                    "topK": 40,
+                    "maxOutputTokens": 100,
+                    "stopSequences": ["END"],
+                    "seed": 7,
+                    "presencePenalty": 0.5,
+                },
+            })
+        );
+    }
+}
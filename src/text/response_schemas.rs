@@ -1,51 +1,311 @@
-use crate::config::prompt::Message;
+use crate::config::prompt::{Message, TokenUsage};
+use crate::utils::ApiError;
 use serde::Deserialize;
 use std::fmt::Debug;
 
+/// text of the answer, whether it was cut because it reached the max tokens
+/// and the tokens used when the api reports them
+#[derive(Debug, PartialEq)]
+pub(super) struct Answer {
+    pub content: String,
+    pub truncated: bool,
+    pub usage: Option<TokenUsage>,
+}
+
 // OpenAi
 #[derive(Debug, Deserialize)]
 pub(super) struct OpenAiResponse {
     pub choices: Vec<MessageWrapper>,
+    #[serde(default)]
+    pub usage: Option<OpenAiUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct OpenAiUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+impl From<OpenAiUsage> for TokenUsage {
+    fn from(value: OpenAiUsage) -> Self {
+        TokenUsage {
+            input_tokens: value.prompt_tokens,
+            output_tokens: value.completion_tokens,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub(super) struct MessageWrapper {
     pub message: Message,
+    pub finish_reason: Option<String>,
 }
 
-impl From<OpenAiResponse> for String {
+impl From<OpenAiResponse> for Answer {
     fn from(value: OpenAiResponse) -> Self {
-        value.choices.first().unwrap().message.content.to_owned()
+        let choice = value.choices.into_iter().next().unwrap();
+        Answer {
+            content: choice.message.content,
+            truncated: choice.finish_reason.as_deref() == Some("length"),
+            usage: value.usage.map(TokenUsage::from),
+        }
     }
 }
 
 // Anthropic
 #[derive(Debug, Deserialize)]
-pub(super) struct AnthropicMessage {
-    pub text: String,
-    #[serde(rename(serialize = "type", deserialize = "type"))]
+pub(super) struct AnthropicContentBlock {
+    #[serde(rename = "type")]
     pub _type: String,
+    #[serde(default)]
+    pub text: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct AnthropicResponse {
+    pub content: Vec<AnthropicContentBlock>,
+    pub stop_reason: Option<String>,
+    /// same field names as smartcat's
+    #[serde(default)]
+    pub usage: Option<TokenUsage>,
 }
 
-impl From<AnthropicResponse> for String {
+impl From<AnthropicResponse> for Answer {
     fn from(value: AnthropicResponse) -> Self {
-        value.content.first().unwrap().text.to_owned()
+        Answer {
+            content: value
+                .content
+                .into_iter()
+                .filter(|block| block._type == "text")
+                .map(|block| block.text)
+                .collect(),
+            truncated: value.stop_reason.as_deref() == Some("max_tokens"),
+            usage: value.usage,
+        }
     }
 }
 
+// Gemini
 #[derive(Debug, Deserialize)]
-pub(super) struct AnthropicResponse {
-    pub content: Vec<AnthropicMessage>,
+#[serde(rename_all = "camelCase")]
+pub(super) struct GeminiResponse {
+    /// missing when the prompt was blocked
+    #[serde(default)]
+    pub candidates: Vec<GeminiCandidate>,
+    #[serde(default)]
+    pub prompt_feedback: Option<GeminiPromptFeedback>,
+    #[serde(default)]
+    pub usage_metadata: Option<GeminiUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct GeminiPromptFeedback {
+    #[serde(default)]
+    pub block_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct GeminiUsage {
+    #[serde(default)]
+    pub prompt_token_count: u32,
+    /// missing when the answer is empty
+    #[serde(default)]
+    pub candidates_token_count: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct GeminiCandidate {
+    /// missing when the answer was blocked
+    #[serde(default)]
+    pub content: Option<GeminiContent>,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct GeminiContent {
+    #[serde(default)]
+    pub parts: Vec<GeminiPart>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct GeminiPart {
+    #[serde(default)]
+    pub text: String,
+}
+
+impl TryFrom<GeminiResponse> for Answer {
+    type Error = ApiError;
+
+    fn try_from(value: GeminiResponse) -> Result<Self, Self::Error> {
+        let Some(candidate) = value.candidates.into_iter().next() else {
+            let reason = value
+                .prompt_feedback
+                .and_then(|feedback| feedback.block_reason)
+                .map(|reason| format!("the prompt was blocked ({})", reason))
+                .unwrap_or_else(|| "the response has no candidate".to_string());
+            return Err(ApiError::Blocked(reason));
+        };
+        Ok(Answer {
+            content: candidate
+                .content
+                .map(|content| content.parts.into_iter().map(|part| part.text).collect())
+                .unwrap_or_default(),
+            truncated: candidate.finish_reason.as_deref() == Some("MAX_TOKENS"),
+            usage: value.usage_metadata.map(|usage| TokenUsage {
+                input_tokens: usage.prompt_token_count,
+                output_tokens: usage.candidates_token_count,
+            }),
+        })
+    }
 }
 
 // Ollama
 #[derive(Debug, Deserialize)]
 pub(super) struct OllamaResponse {
     pub message: Message,
+    pub done_reason: Option<String>,
+    /// missing when the prompt was cached by ollama
+    #[serde(default)]
+    pub prompt_eval_count: Option<u32>,
+    #[serde(default)]
+    pub eval_count: Option<u32>,
 }
 
-impl From<OllamaResponse> for String {
+impl From<OllamaResponse> for Answer {
     fn from(value: OllamaResponse) -> Self {
-        value.message.content
+        Answer {
+            content: value.message.content,
+            truncated: value.done_reason.as_deref() == Some("length"),
+            usage: value.eval_count.map(|eval_count| TokenUsage {
+                input_tokens: value.prompt_eval_count.unwrap_or_default(),
+                output_tokens: eval_count,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anthropic_text_blocks_are_concatenated() {
+        let response: AnthropicResponse = serde_json::from_str(
+            r#"{
+                "content": [
+                    {"type": "text", "text": "first part, "},
+                    {"type": "tool_use", "id": "toolu_1", "name": "tool", "input": {}},
+                    {"type": "text", "text": "second part"}
+                ],
+                "stop_reason": "end_turn"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            Answer::from(response),
+            Answer {
+                content: "first part, second part".to_string(),
+                truncated: false,
+                usage: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_gemini_parts_are_concatenated() {
+        let response: GeminiResponse = serde_json::from_str(
+            r#"{
+                "candidates": [{
+                    "content": {"role": "model", "parts": [{"text": "first part, "}, {"text": "second part"}]},
+                    "finishReason": "STOP"
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            Answer::try_from(response).unwrap(),
+            Answer {
+                content: "first part, second part".to_string(),
+                truncated: false,
+                usage: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_gemini_blocked_prompt_is_an_error() {
+        let response: GeminiResponse = serde_json::from_str(
+            r#"{"promptFeedback": {"blockReason": "SAFETY"}, "usageMetadata": {"promptTokenCount": 8}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            Answer::try_from(response).unwrap_err().to_string(),
+            "API returned no answer: the prompt was blocked (SAFETY)"
+        );
+    }
+
+    #[test]
+    fn test_truncated_answers() {
+        let openai: OpenAiResponse = serde_json::from_str(
+            r#"{"choices": [{"message": {"role": "assistant", "content": "a"}, "finish_reason": "length"}]}"#,
+        )
+        .unwrap();
+        let anthropic: AnthropicResponse = serde_json::from_str(
+            r#"{"content": [{"type": "text", "text": "a"}], "stop_reason": "max_tokens"}"#,
+        )
+        .unwrap();
+        let ollama: OllamaResponse = serde_json::from_str(
+            r#"{"message": {"role": "assistant", "content": "a"}, "done_reason": "length"}"#,
+        )
+        .unwrap();
+        let gemini: GeminiResponse = serde_json::from_str(
+            r#"{"candidates": [{"content": {"role": "model", "parts": [{"text": "a"}]}, "finishReason": "MAX_TOKENS"}]}"#,
+        )
+        .unwrap();
+        let complete: OpenAiResponse = serde_json::from_str(
+            r#"{"choices": [{"message": {"role": "assistant", "content": "a"}, "finish_reason": "stop"}]}"#,
+        )
+        .unwrap();
+
+        assert!(Answer::from(openai).truncated);
+        assert!(Answer::from(anthropic).truncated);
+        assert!(Answer::from(ollama).truncated);
+        assert!(Answer::try_from(gemini).unwrap().truncated);
+        assert!(!Answer::from(complete).truncated);
+    }
+
+    #[test]
+    fn test_token_usage() {
+        let openai: OpenAiResponse = serde_json::from_str(
+            r#"{"choices": [{"message": {"role": "assistant", "content": "a"}, "finish_reason": "stop"}], "usage": {"prompt_tokens": 12, "completion_tokens": 3, "total_tokens": 15}}"#,
+        )
+        .unwrap();
+        let anthropic: AnthropicResponse = serde_json::from_str(
+            r#"{"content": [{"type": "text", "text": "a"}], "stop_reason": "end_turn", "usage": {"input_tokens": 12, "output_tokens": 3}}"#,
+        )
+        .unwrap();
+        let gemini: GeminiResponse = serde_json::from_str(
+            r#"{"candidates": [{"content": {"role": "model", "parts": [{"text": "a"}]}, "finishReason": "STOP"}], "usageMetadata": {"promptTokenCount": 12, "candidatesTokenCount": 3, "totalTokenCount": 15}}"#,
+        )
+        .unwrap();
+        let ollama: OllamaResponse = serde_json::from_str(
+            r#"{"message": {"role": "assistant", "content": "a"}, "done_reason": "stop", "prompt_eval_count": 12, "eval_count": 3}"#,
+        )
+        .unwrap();
+        let expected = Some(TokenUsage {
+            input_tokens: 12,
+            output_tokens: 3,
+        });
+
+        assert_eq!(Answer::from(openai).usage, expected);
+        assert_eq!(Answer::from(anthropic).usage, expected);
+        assert_eq!(Answer::try_from(gemini).unwrap().usage, expected);
+        assert_eq!(Answer::from(ollama).usage, expected);
     }
 }
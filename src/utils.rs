@@ -1,18 +1,97 @@
+use std::fmt;
+use std::fs;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 pub const IS_NONINTERACTIVE_ENV_VAR: &str = "SMARTCAT_NONINTERACTIVE";
 
+#[derive(Debug)]
+pub enum ApiError {
+    /// the api could not be reached or the answer could not be read
+    Request(reqwest::Error),
+    /// the api answered with an error status
+    Status {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+    /// the api reported an error in the middle of a streamed answer
+    Stream(String),
+    /// the api answered without any answer, e.g. the prompt was blocked
+    Blocked(String),
+    /// the request can't be made, e.g. the api or the model isn't configured
+    Config(String),
+}
+
+impl ApiError {
+    /// whether another backend may succeed where this one failed,
+    /// e.g. the provider is overloaded, down or the quota is exhausted
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ApiError::Request(e) => e.is_timeout() || e.is_connect(),
+            ApiError::Status { status, .. } => {
+                status.as_u16() == 429 || status.as_u16() == 529 || status.is_server_error()
+            }
+            ApiError::Stream(_) | ApiError::Blocked(_) | ApiError::Config(_) => false,
+        }
+    }
+
+    /// the api refused the request itself, e.g. an unknown model or parameter
+    pub fn is_client_error(&self) -> bool {
+        matches!(self, ApiError::Status { status, .. } if status.as_u16() == 400 || status.as_u16() == 404)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::Request(e) => write!(f, "API request failed: {}", e),
+            ApiError::Status { status, body } => {
+                write!(f, "API request failed with status {}: {}", status, body)
+            }
+            ApiError::Stream(message) => write!(f, "API request failed: {}", message),
+            ApiError::Blocked(reason) => write!(f, "API returned no answer: {}", reason),
+            ApiError::Config(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(e: reqwest::Error) -> Self {
+        ApiError::Request(e)
+    }
+}
+
 /// clean error logging
-pub fn handle_api_response<T: serde::de::DeserializeOwned + Into<String>>(
+pub fn handle_api_response<T: serde::de::DeserializeOwned>(
     response: reqwest::blocking::Response,
-) -> String {
+) -> Result<T, ApiError> {
     let status = response.status();
     if response.status().is_success() {
-        response.json::<T>().unwrap().into()
+        Ok(response.json::<T>()?)
     } else {
-        let error_text = response.text().unwrap();
-        panic!("API request failed with status {}: {}", status, error_text);
+        let body = response.text()?;
+        Err(ApiError::Status { status, body })
+    }
+}
+
+/// `https://api.openai.com/v1/chat/completions` -> `https://api.openai.com/v1/models`
+pub fn sibling_url(url: &str, endpoint: &str, sibling: &str) -> String {
+    let url = url.trim_end_matches('/');
+    match url.strip_suffix(endpoint) {
+        Some(base) => format!("{}{}", base, sibling),
+        None => format!("{}/{}", url, sibling),
     }
 }
 
+pub fn now_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time is before the unix epoch")
+        .as_secs()
+}
+
 pub fn is_interactive() -> bool {
     std::env::var(IS_NONINTERACTIVE_ENV_VAR).unwrap_or_default() != "1"
 }
@@ -24,3 +103,102 @@ pub fn read_user_input() -> String {
         .expect("Failed to read line");
     user_input.trim().to_string()
 }
+
+/// open the text in `$VISUAL` or `$EDITOR`, `vi` otherwise, and return it once the editor is closed
+pub fn edit_in_editor(text: &str) -> std::io::Result<String> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    // created with a random name and only readable by the user
+    let file = tempfile::Builder::new()
+        .prefix("smartcat-edit-")
+        .suffix(".md")
+        .tempfile()?;
+    let path = file.path();
+    fs::write(path, text)?;
+
+    // through the shell for editors configured with arguments, e.g. `code --wait`
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(path)
+        .status();
+    // the file is removed when dropped
+    let edited = fs::read_to_string(path);
+
+    if !status?.success() {
+        return Err(std::io::Error::other(format!(
+            "the editor `{}` exited with an error",
+            editor
+        )));
+    }
+    edited
+}
+
+/// the content of an answer made of a single fenced code block, the answer otherwise
+pub fn unfence(answer: &str) -> &str {
+    match answer
+        .trim()
+        .strip_prefix("```")
+        .and_then(|rest| rest.strip_suffix("```"))
+        .and_then(|inner| inner.split_once('\n'))
+    {
+        Some((_, code)) if !code.contains("\n```") => code,
+        _ => answer,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_error_retryable_statuses() {
+        for (status, retryable) in [
+            (429, true),
+            (500, true),
+            (503, true),
+            (529, true),
+            (400, false),
+            (401, false),
+            (404, false),
+        ] {
+            let error = ApiError::Status {
+                status: reqwest::StatusCode::from_u16(status).unwrap(),
+                body: String::new(),
+            };
+            assert_eq!(error.is_retryable(), retryable, "status {}", status);
+        }
+    }
+
+    #[test]
+    fn test_sibling_url() {
+        assert_eq!(
+            sibling_url(
+                "https://api.openai.com/v1/chat/completions",
+                "chat/completions",
+                "models"
+            ),
+            "https://api.openai.com/v1/models"
+        );
+        assert_eq!(
+            sibling_url(
+                "https://api.anthropic.com/v1/messages",
+                "messages",
+                "models"
+            ),
+            "https://api.anthropic.com/v1/models"
+        );
+    }
+
+    #[test]
+    fn test_unfence() {
+        assert_eq!(unfence("```rust\nlet x = 1;\n```"), "let x = 1;\n");
+        assert_eq!(unfence("let x = 1;"), "let x = 1;");
+        assert_eq!(
+            unfence("```\na\n```\ntext\n```\nb\n```"),
+            "```\na\n```\ntext\n```\nb\n```"
+        );
+    }
+}